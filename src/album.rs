@@ -1,17 +1,97 @@
-use std::fs;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
 use crossbeam_channel::Sender;
-use google_photoslibrary1::{Album, MediaItem, Result as PLResult, SearchMediaItemsRequest};
+use google_photoslibrary1::{Album, Error, MediaItem, Result as PLResult, SearchMediaItemsRequest};
 use hyper::status::StatusCode;
 use log::{debug, error, info};
 use regex::Regex;
 
-use crate::filesystem::{create_symlink, FilesystemSafeEscaper};
+use crate::filesystem::FilesystemSafeEscaper;
 use crate::filtering::Matcher;
 use crate::library::Library;
+use crate::shutdown;
+use crate::storage::Storage;
 
 const MEDIA_SEARCH_MAX_PAGE_SIZE: i32 = 100;
+const MIN_ORDINAL_WIDTH: usize = 4;
+
+/// Name of an album link prefixed with the (1-based) position of the media in the album,
+/// zero-padded so that the links sort in album order, e.g. `0007_IMG_1234.JPG`.
+fn ordinal_filename(position: usize, total: usize, filename: &str) -> String {
+    let width = std::cmp::max(MIN_ORDINAL_WIDTH, total.to_string().len());
+    format!("{:0width$}_{}", position + 1, filename, width = width)
+}
+
+/// Folder inside a shared album for the media's contributor. Media without contributor
+/// information stays in the album folder itself.
+fn contributor_path(storage: &dyn Storage, album_path: &Path, media: &MediaItem) -> PathBuf {
+    let name = media
+        .contributor_info
+        .as_ref()
+        .and_then(|info| info.display_name.as_ref());
+    if let Some(name) = name {
+        let path = album_path.join(name.escape());
+        match storage.create_dir(&path) {
+            Ok(()) => return path,
+            Err(e) => error!("Could not create contributor folder {:?}: {}", path, e),
+        }
+    }
+    album_path.to_path_buf()
+}
+
+/// Removes ordinal prefixed links that are no longer part of the album (either because the
+/// media was removed, because the album was reordered and the media got a new position, or
+/// because `--album-order` was turned off). Only links this code made are removed: the name
+/// is the ordinal prefix followed by the name of the media in the library it points to.
+fn remove_stale_ordinal_links(storage: &dyn Storage, album_path: &Path, current: &HashSet<String>) {
+    let ordinal = Regex::new(r"^\d{4,}_(.+)$").unwrap();
+    let entries = match storage.list(album_path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Could not read album folder {:?}: {}", album_path, e);
+            return;
+        }
+    };
+    for path in entries
+        .iter()
+        .filter(|path| path.parent() == Some(album_path))
+    {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if current.contains(&name) {
+            continue;
+        }
+        let media = match ordinal.captures(&name) {
+            Some(captures) => PathBuf::from(&captures[1]),
+            None => continue,
+        };
+        if storage.is_link(&media, path) {
+            debug!("Removing stale album link {:?}", path);
+            storage
+                .remove(path)
+                .unwrap_or_else(|e| error!("Could not remove {:?}: {}", path, e));
+        }
+    }
+}
+
+/// Whether `path` in an album folder is a link this code made to media in the library: named
+/// after the media or, with `--album-order`, after its position in the album and the media.
+pub fn is_album_link(storage: &dyn Storage, path: &Path) -> bool {
+    let ordinal = Regex::new(r"^\d{4,}_(.+)$").unwrap();
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let unprefixed = ordinal
+        .captures(&name)
+        .map(|captures| captures[1].to_string());
+    std::iter::once(name.clone())
+        .chain(unprefixed)
+        .any(|media| storage.is_link(Path::new(&media), path))
+}
 
 impl Matcher for Album {
     fn matches(&self, regex: Option<Regex>) -> bool {
@@ -32,20 +112,21 @@ impl Matcher for Album {
 
 pub trait AlbumFetcher {
     fn safe_title(&self) -> String;
-    fn create_dir(&self, basepath: &Path) -> Result<PathBuf, std::io::Error>;
+    fn create_dir(&self, storage: &dyn Storage) -> Result<PathBuf, std::io::Error>;
     fn get_album_media(&self, library: &Library) -> PLResult<Vec<MediaItem>>;
+    /// Links the album media in the album folder and returns the names of the links.
     fn link_media_items(
         &self,
         library: &Library,
-        basepath: &Path,
         album_path: &PathBuf,
-    ) -> PLResult<()>;
+    ) -> PLResult<HashSet<String>>;
+    /// Queues the album media for download and returns how many were queued.
     fn download_media_items(
         &self,
         library: &Library,
         album_path: &PathBuf,
         work_sender: &Sender<(MediaItem, PathBuf)>,
-    ) -> PLResult<()>;
+    ) -> PLResult<usize>;
 }
 
 impl AlbumFetcher for Album {
@@ -63,11 +144,10 @@ impl AlbumFetcher for Album {
         }
     }
 
-    fn create_dir(&self, basepath: &Path) -> Result<PathBuf, std::io::Error> {
-        let title = self.safe_title();
-        let path = basepath.join(title);
+    fn create_dir(&self, storage: &dyn Storage) -> Result<PathBuf, std::io::Error> {
+        let path = PathBuf::from(self.safe_title());
         debug!("Creating album folder: {}", &path.to_str().unwrap());
-        fs::create_dir_all(&path)?;
+        storage.create_dir(&path)?;
         Ok(path)
     }
 
@@ -77,6 +157,9 @@ impl AlbumFetcher for Album {
         if let Some(id) = &self.id {
             let mut page_token = None;
             loop {
+                if shutdown::requested() {
+                    return Err(Error::Cancelled);
+                }
                 let req = SearchMediaItemsRequest {
                     album_id: Some(id.to_string()),
                     page_size: Some(MEDIA_SEARCH_MAX_PAGE_SIZE),
@@ -110,25 +193,42 @@ impl AlbumFetcher for Album {
     fn link_media_items(
         &self,
         library: &Library,
-        basepath: &Path,
         album_path: &PathBuf,
-    ) -> PLResult<()> {
+    ) -> PLResult<HashSet<String>> {
         let album_media = self.get_album_media(library)?;
         info!(
             "Linking album media items ({}) for album {}",
             album_media.len(),
             self.safe_title()
         );
+        let storage = library.storage();
+        let album_order = library.config().options().album_order;
+        let mut links = HashSet::new();
         album_media
             .iter()
-            .filter(|media| media.matches(library.config().options().media_filter))
-            .for_each(|media| {
+            .enumerate()
+            .filter(|(_, media)| media.matches(library.config().options().media_filter))
+            .for_each(|(position, media)| {
                 if let Some(filename) = &media.filename {
                     let fname = filename.escape();
-                    create_symlink(&basepath.join(&fname), &album_path.join(&fname));
+                    let link_name = if album_order {
+                        ordinal_filename(position, album_media.len(), &fname)
+                    } else {
+                        fname.clone()
+                    };
+                    let link = album_path.join(&link_name);
+                    match storage.link(Path::new(&fname), &link) {
+                        Ok(()) => (),
+                        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                            debug!("Album link {:?} already exists", link)
+                        }
+                        Err(e) => error!("Could not point {:?} to {:?}: {}", link, fname, e),
+                    }
+                    links.insert(link_name);
                 }
             });
-        Ok(())
+        remove_stale_ordinal_links(storage.as_ref(), album_path, &links);
+        Ok(links)
     }
 
     fn download_media_items(
@@ -136,21 +236,85 @@ impl AlbumFetcher for Album {
         library: &Library,
         album_path: &PathBuf,
         work_sender: &Sender<(MediaItem, PathBuf)>,
-    ) -> PLResult<()> {
+    ) -> PLResult<usize> {
         let album_media = self.get_album_media(library)?;
         info!(
             "Downloading album media items ({}) for album {}",
             album_media.len(),
             self.safe_title()
         );
+        let storage = library.storage();
+        let contributor_folders = library.config().options().contributor_folders;
+        let mut queued = 0;
         album_media
             .iter()
             .filter(|media| media.matches(library.config().options().media_filter))
             .for_each(|media| {
+                let path = if contributor_folders {
+                    contributor_path(storage.as_ref(), album_path, media)
+                } else {
+                    album_path.to_path_buf()
+                };
                 work_sender
-                    .send((media.clone(), album_path.to_path_buf()))
+                    .send((media.clone(), path))
                     .unwrap_or_else(|e| error!("Error sending to be processed: {}", e));
+                queued += 1;
             });
-        Ok(())
+        Ok(queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ordinal_filename, remove_stale_ordinal_links};
+    use crate::filesystem::LinkMode;
+    use crate::storage::{LocalStorage, Storage};
+    use std::collections::HashSet;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_ordinal_filename() {
+        assert_eq!(ordinal_filename(6, 20, "IMG_1234.JPG"), "0007_IMG_1234.JPG");
+        assert_eq!(ordinal_filename(0, 9999, "a.jpg"), "0001_a.jpg");
+        assert_eq!(ordinal_filename(41, 12000, "a.jpg"), "00042_a.jpg");
+    }
+
+    #[test]
+    fn test_remove_stale_ordinal_links() {
+        let root = std::env::temp_dir().join(format!("gpb-album-{}", std::process::id()));
+        let album = root.join("Album");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(root.join("a.jpg"), "a").unwrap();
+        fs::write(album.join("2019_trip.jpg"), "trip").unwrap();
+        fs::write(album.join("0003_a.jpg"), "not a").unwrap();
+        let storage = LocalStorage::new(&root, LinkMode::Symlink);
+        for link in &["0001_a.jpg", "0002_a.jpg", "a.jpg"] {
+            storage
+                .link(Path::new("a.jpg"), &Path::new("Album").join(link))
+                .unwrap();
+        }
+
+        let current = ["0002_a.jpg".to_string()].iter().cloned().collect();
+        remove_stale_ordinal_links(&storage, Path::new("Album"), &current);
+        let mut left = fs::read_dir(&album)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["0002_a.jpg", "0003_a.jpg", "2019_trip.jpg", "a.jpg"]
+        );
+
+        // With --album-order turned off, the prefixed links go.
+        let current = ["a.jpg".to_string()]
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>();
+        remove_stale_ordinal_links(&storage, Path::new("Album"), &current);
+        assert!(!album.join("0002_a.jpg").exists());
+        assert!(album.join("2019_trip.jpg").exists());
+        fs::remove_dir_all(&root).unwrap();
     }
 }
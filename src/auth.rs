@@ -1,26 +1,61 @@
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Write};
 use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, Utc};
 use hyper::net::HttpsConnector;
 use hyper::Client;
 use hyper_rustls::TlsClient;
+use log::info;
+use reqwest::Url;
+use serde::Deserialize;
 use yup_oauth2::{
-    ApplicationSecret, Authenticator, DefaultAuthenticatorDelegate, DiskTokenStorage, FlowType,
+    read_application_secret, ApplicationSecret, Authenticator, DefaultAuthenticatorDelegate,
+    DiskTokenStorage, FlowType, GetToken, Token, TokenStorage,
 };
 
-pub type LibraryAuthenticator =
-    Authenticator<DefaultAuthenticatorDelegate, DiskTokenStorage, Client>;
+pub type LibraryAuthenticator = Authenticator<DefaultAuthenticatorDelegate, TokenStore, Client>;
 
 use crate::config::Config;
+use crate::encryption;
+use crate::token_storage::{EncryptedTokenStorage, TokenStore, TOKEN_PASSPHRASE_VAR};
 
 fn get_available_port() -> Option<u16> {
     (8080..65535).find(|port| TcpListener::bind(("127.0.0.1", *port)).is_ok())
 }
 
-pub fn authenticate(config: &Config) -> LibraryAuthenticator {
-    let client = Client::with_connector(HttpsConnector::new(TlsClient::new()));
-    let secret = ApplicationSecret {
-        client_id: env!("GOOGLE_PHOTOS_BACKUP_CLIENT_ID").to_string(),
-        client_secret: env!("GOOGLE_PHOTOS_BACKUP_CLIENT_SECRET").to_string(),
+const CLIENT_ID_VAR: &str = "GOOGLE_PHOTOS_BACKUP_CLIENT_ID";
+const CLIENT_SECRET_VAR: &str = "GOOGLE_PHOTOS_BACKUP_CLIENT_SECRET";
+const CLIENT_SECRET_FILE: &str = "client_secret.json";
+const TOKEN_FILE: &str = "credentials.json";
+const ENCRYPTED_TOKEN_FILE: &str = "credentials.enc";
+/// Where Google sends the browser after authorization. Without a browser on this machine the
+/// page fails to load, and its URL (with the code) is pasted back instead.
+const NO_BROWSER_REDIRECT_URI: &str = "http://localhost";
+
+const TOKEN_INFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+/// Scopes used by the backup (listing media and albums).
+pub const SCOPES: [&str; 2] = [
+    "https://www.googleapis.com/auth/photoslibrary",
+    "https://www.googleapis.com/auth/photoslibrary.readonly",
+];
+/// Scopes requested when logging in: the backup scopes, plus the email address to tell which
+/// account the token is for.
+const LOGIN_SCOPES: [&str; 3] = [
+    "https://www.googleapis.com/auth/photoslibrary",
+    "https://www.googleapis.com/auth/photoslibrary.readonly",
+    "email",
+];
+
+fn installed_app_secret(client_id: &str, client_secret: &str) -> ApplicationSecret {
+    ApplicationSecret {
+        client_id: client_id.to_string(),
+        client_secret: client_secret.to_string(),
         token_uri: "https://oauth2.googleapis.com/token".to_string(),
         auth_uri: "https://accounts.google.com/o/oauth2/auth".to_string(),
         redirect_uris: vec![
@@ -28,19 +63,397 @@ pub fn authenticate(config: &Config) -> LibraryAuthenticator {
             "urn:ietf:wg:oauth:2.0:oob".to_string(),
         ],
         ..Default::default()
+    }
+}
+
+/// `client_secret.json` in the user config dir, as downloaded from the Google Cloud console.
+pub fn default_client_secret_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("google_photos_backup").join(CLIENT_SECRET_FILE))
+}
+
+/// The OAuth client to authenticate with, from the first of: the client secret file given on
+/// the command line, the environment variables, the client secret file in the user config dir,
+/// or the client built into the binary.
+pub fn application_secret(client_secret: Option<&Path>) -> io::Result<ApplicationSecret> {
+    if let Some(path) = client_secret {
+        return read_application_secret(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)));
+    }
+    if let (Ok(client_id), Ok(client_secret)) =
+        (env::var(CLIENT_ID_VAR), env::var(CLIENT_SECRET_VAR))
+    {
+        return Ok(installed_app_secret(&client_id, &client_secret));
+    }
+    if let Some(path) = default_client_secret_path().filter(|path| path.exists()) {
+        return read_application_secret(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)));
+    }
+    match (
+        option_env!("GOOGLE_PHOTOS_BACKUP_CLIENT_ID"),
+        option_env!("GOOGLE_PHOTOS_BACKUP_CLIENT_SECRET"),
+    ) {
+        (Some(client_id), Some(client_secret)) => {
+            Ok(installed_app_secret(client_id, client_secret))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no OAuth client found: use --client-secret, set {} and {} or put a {} in {:?}",
+                CLIENT_ID_VAR,
+                CLIENT_SECRET_VAR,
+                CLIENT_SECRET_FILE,
+                default_client_secret_path().and_then(|path| path.parent().map(Path::to_path_buf))
+            ),
+        )),
+    }
+}
+
+/// Hash of the scopes, as computed by the authenticator to look up tokens.
+fn scopes_hash(scopes: &[&str]) -> u64 {
+    let mut sorted = scopes.to_vec();
+    sorted.sort();
+    let mut hasher = DefaultHasher::new();
+    sorted.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The token of an account: where it's stored, the OAuth client it's for and, when it's
+/// encrypted, the file with its passphrase (instead of the environment variable).
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub cache: PathBuf,
+    pub client_secret: Option<PathBuf>,
+    pub token_key_file: Option<PathBuf>,
+}
+
+impl Credentials {
+    pub fn new(
+        cache: PathBuf,
+        client_secret: Option<PathBuf>,
+        token_key_file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            cache,
+            client_secret,
+            token_key_file,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let options = config.options();
+        Self::new(
+            config.cache(),
+            options.client_secret,
+            options.token_key_file,
+        )
+    }
+
+    pub fn logged_in(&self) -> bool {
+        self.token_file().exists()
+    }
+
+    fn encrypted(&self) -> bool {
+        self.cache.join(ENCRYPTED_TOKEN_FILE).exists()
+    }
+
+    pub fn token_file(&self) -> PathBuf {
+        if self.encrypted() {
+            self.cache.join(ENCRYPTED_TOKEN_FILE)
+        } else {
+            self.cache.join(TOKEN_FILE)
+        }
+    }
+
+    fn passphrase(&self) -> io::Result<Vec<u8>> {
+        encryption::read_passphrase(self.token_key_file.as_deref(), TOKEN_PASSPHRASE_VAR)
+            .map_err(|e| io::Error::new(e.kind(), format!("token passphrase: {}", e)))
+    }
+
+    /// The storage of the token: encrypted if it was logged in with an encrypted token.
+    fn storage(&self) -> io::Result<TokenStore> {
+        if self.encrypted() {
+            self.create_storage(Some(self.passphrase()?))
+        } else {
+            self.create_storage(None)
+        }
+    }
+
+    fn create_storage(&self, passphrase: Option<Vec<u8>>) -> io::Result<TokenStore> {
+        Ok(match passphrase {
+            Some(passphrase) => TokenStore::Encrypted(EncryptedTokenStorage::new(
+                &self.cache.join(ENCRYPTED_TOKEN_FILE),
+                passphrase,
+            )?),
+            None => TokenStore::Plain(DiskTokenStorage::new(
+                &self.cache.join(TOKEN_FILE).to_string_lossy().to_string(),
+            )?),
+        })
+    }
+
+    fn remove_tokens(&self) -> io::Result<()> {
+        for file in &[TOKEN_FILE, ENCRYPTED_TOKEN_FILE] {
+            match std::fs::remove_file(self.cache.join(file)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    fn stored_token(&self) -> io::Result<Option<Token>> {
+        self.storage()?.get(scopes_hash(&SCOPES), &SCOPES.to_vec())
+    }
+}
+
+/// Authorization code from what was pasted: either the code itself or the whole URL the
+/// browser was redirected to.
+fn authorization_code(pasted: &str) -> io::Result<String> {
+    let pasted = pasted.trim();
+    let url = match Url::parse(pasted) {
+        Ok(url) => url,
+        Err(_) if !pasted.is_empty() => return Ok(pasted.to_string()),
+        Err(_) => return Err(io::Error::other("no authorization code given")),
     };
+    let mut query = url.query_pairs();
+    if let Some((_, error)) = query.clone().find(|(key, _)| key == "error") {
+        return Err(io::Error::other(format!("authorization failed: {}", error)));
+    }
+    query
+        .find(|(key, _)| key == "code")
+        .map(|(_, code)| code.to_string())
+        .ok_or_else(|| io::Error::other(format!("no authorization code in {}", url)))
+}
+
+/// Authorizes without a browser on this machine: the authorization URL is opened on another
+/// machine, and the code (or the URL the browser ends up on) is pasted back here.
+fn login_without_browser(secret: &ApplicationSecret) -> io::Result<Token> {
+    let url = Url::parse_with_params(
+        &secret.auth_uri,
+        &[
+            ("scope", LOGIN_SCOPES.join(" ").as_str()),
+            ("redirect_uri", NO_BROWSER_REDIRECT_URI),
+            ("response_type", "code"),
+            ("access_type", "offline"),
+            ("prompt", "consent"),
+            ("client_id", secret.client_id.as_str()),
+        ],
+    )
+    .map_err(io::Error::other)?;
+    println!(
+        "Open this URL in a browser on any machine and authorize the access:\n\n{}\n",
+        url
+    );
+    println!(
+        "The browser then tries to load a page on localhost, which fails. Paste the URL of that \
+         page (or just the code in it) here:"
+    );
+    print!("> ");
+    io::stdout().flush()?;
+    let mut pasted = String::new();
+    io::stdin().lock().read_line(&mut pasted)?;
+    let code = authorization_code(&pasted)?;
+
+    let mut response = reqwest::Client::new()
+        .post(&secret.token_uri)
+        .form(&[
+            ("code", code.as_str()),
+            ("client_id", secret.client_id.as_str()),
+            ("client_secret", secret.client_secret.as_str()),
+            ("redirect_uri", NO_BROWSER_REDIRECT_URI),
+            ("grant_type", "authorization_code"),
+        ])
+        .send()
+        .map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        let body = response.text().unwrap_or_default();
+        return Err(io::Error::other(format!(
+            "could not get a token ({}): {}",
+            response.status(),
+            body
+        )));
+    }
+    let mut token: Token = response.json().map_err(io::Error::other)?;
+    token.set_expiry_absolute();
+    Ok(token)
+}
 
-    let storage = if let Some(token_path) = config.cache().join("credentials.json").to_str() {
-        DiskTokenStorage::new(&token_path.to_string()).expect("credentials cache")
+/// Authorizes access to Google Photos and stores the token, through the browser on this
+/// machine or, with `no_browser`, by pasting the code from another machine. With `encrypt`,
+/// the token is stored encrypted with the token passphrase.
+pub fn login(credentials: &Credentials, no_browser: bool, encrypt: bool) -> io::Result<()> {
+    let secret = application_secret(credentials.client_secret.as_deref())?;
+    let passphrase = if encrypt {
+        Some(credentials.passphrase()?)
     } else {
-        panic!("Could not setup disk storage");
+        None
     };
-
-    let flow = if let Some(port) = get_available_port() {
-        Some(FlowType::InstalledRedirect(port.into()))
+    // Logging in replaces any token stored before, including tokens refreshed per scope.
+    if no_browser {
+        let token = login_without_browser(&secret)?;
+        credentials.remove_tokens()?;
+        credentials.create_storage(passphrase)?.set(
+            scopes_hash(&LOGIN_SCOPES),
+            &LOGIN_SCOPES.to_vec(),
+            Some(token),
+        )?;
     } else {
-        Some(FlowType::InstalledInteractive)
+        credentials.remove_tokens()?;
+        let storage = credentials.create_storage(passphrase)?;
+        let client = Client::with_connector(HttpsConnector::new(TlsClient::new()));
+        let flow = match get_available_port() {
+            Some(port) => FlowType::InstalledRedirect(port.into()),
+            None => FlowType::InstalledInteractive,
+        };
+        Authenticator::new(
+            &secret,
+            DefaultAuthenticatorDelegate,
+            client,
+            storage,
+            Some(flow),
+        )
+        .token(&LOGIN_SCOPES)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    }
+    info!(
+        "Logged in, the token is stored in {:?}",
+        credentials.token_file()
+    );
+    Ok(())
+}
+
+/// Authenticator using the token stored by `login`. It never asks for authorization, so it
+/// fails instead of blocking on input when there's no token.
+pub fn authenticator(credentials: &Credentials) -> io::Result<LibraryAuthenticator> {
+    let secret = application_secret(credentials.client_secret.as_deref())?;
+    let storage = credentials.storage()?;
+    if storage
+        .get(scopes_hash(&SCOPES), &SCOPES.to_vec())?
+        .is_none()
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "not logged in to Google Photos",
+        ));
+    }
+    let client = Client::with_connector(HttpsConnector::new(TlsClient::new()));
+    Ok(Authenticator::new(
+        &secret,
+        DefaultAuthenticatorDelegate,
+        client,
+        storage,
+        Some(FlowType::InstalledInteractive),
+    ))
+}
+
+/// Authenticator for the account of `config`, telling how to log in when there's no token.
+pub fn authenticate(config: &Config) -> io::Result<LibraryAuthenticator> {
+    let credentials = Credentials::from_config(config);
+    if !credentials.logged_in() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "not logged in to Google Photos, run `google-photos-backup auth login{}` first",
+                config
+                    .profile()
+                    .map(|profile| format!(" --profile {}", profile))
+                    .unwrap_or_default()
+            ),
+        ));
+    }
+    authenticator(&credentials)
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenInfo {
+    email: Option<String>,
+    scope: String,
+}
+
+/// What the stored token gives access to.
+#[derive(Debug)]
+pub struct Status {
+    pub token_file: PathBuf,
+    /// Email address of the account, unknown for tokens from before it was requested.
+    pub account: Option<String>,
+    pub scopes: Vec<String>,
+    /// When the current access token expires (it's refreshed as needed).
+    pub expiry: DateTime<Utc>,
+}
+
+/// Status of the token, or None when not logged in. The access token is refreshed if it
+/// expired, which also checks that access wasn't revoked.
+pub fn status(credentials: &Credentials) -> io::Result<Option<Status>> {
+    if credentials.stored_token()?.is_none() {
+        return Ok(None);
+    }
+    let token = authenticator(credentials)?
+        .token(&SCOPES)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let mut response = reqwest::Client::new()
+        .get(TOKEN_INFO_URL)
+        .query(&[("access_token", &token.access_token)])
+        .send()
+        .map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        return Err(io::Error::other(format!(
+            "could not get the token information ({}): {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        )));
+    }
+    let info: TokenInfo = response.json().map_err(io::Error::other)?;
+    Ok(Some(Status {
+        token_file: credentials.token_file(),
+        account: info.email,
+        scopes: info.scope.split_whitespace().map(str::to_string).collect(),
+        expiry: token.expiry_date(),
+    }))
+}
+
+/// Deletes the stored token. Returns false if there was none.
+pub fn logout(credentials: &Credentials) -> io::Result<bool> {
+    let logged_in = credentials.logged_in();
+    credentials.remove_tokens()?;
+    Ok(logged_in)
+}
+
+/// Revokes the access given to the stored token on Google's side, then deletes it. Returns
+/// false if there was no token.
+pub fn revoke(credentials: &Credentials) -> io::Result<bool> {
+    let token = match credentials.stored_token()? {
+        Some(token) => token,
+        None => return Ok(false),
     };
+    // Revoking the refresh token also revokes the access tokens issued with it.
+    let mut response = reqwest::Client::new()
+        .post(REVOKE_URL)
+        .form(&[("token", &token.refresh_token)])
+        .send()
+        .map_err(io::Error::other)?;
+    // An invalid token was already revoked (or expired), which is as good.
+    if !response.status().is_success() && response.status() != reqwest::StatusCode::BAD_REQUEST {
+        return Err(io::Error::other(format!(
+            "could not revoke the token ({}): {}",
+            response.status(),
+            response.text().unwrap_or_default()
+        )));
+    }
+    credentials.remove_tokens()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Authenticator::new(&secret, DefaultAuthenticatorDelegate, client, storage, flow)
+    #[test]
+    fn test_authorization_code() {
+        assert_eq!(
+            authorization_code("http://localhost/?code=4/0Ab%2Bc&scope=x\n").unwrap(),
+            "4/0Ab+c"
+        );
+        assert_eq!(authorization_code(" 4/0Abc \n").unwrap(), "4/0Abc");
+        assert!(authorization_code("http://localhost/?error=access_denied").is_err());
+        assert!(authorization_code("\n").is_err());
+    }
 }
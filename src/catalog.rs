@@ -0,0 +1,170 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dedup::BLOBS_FOLDER;
+use crate::filesystem::normalize;
+use crate::sidecar::Sidecar;
+
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp"];
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "mov", "webm", "ogv"];
+
+/// A media file found in the archive.
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// Path relative to the archive folder.
+    pub path: PathBuf,
+    /// Whether this is an album link to a file in the main library.
+    pub is_link: bool,
+    /// Path relative to the archive folder of the file with the content, which is only
+    /// different from `path` for album links.
+    pub target: PathBuf,
+    pub sidecar: Option<Sidecar>,
+}
+
+impl Item {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn mime_type(&self) -> Option<String> {
+        self.sidecar
+            .as_ref()
+            .and_then(|sidecar| sidecar.mime_type.clone())
+    }
+
+    pub fn creation_time(&self) -> Option<String> {
+        self.sidecar
+            .as_ref()
+            .and_then(|sidecar| sidecar.creation_time.clone())
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.has_type("image/", IMAGE_EXTENSIONS)
+    }
+
+    pub fn is_video(&self) -> bool {
+        self.has_type("video/", VIDEO_EXTENSIONS)
+    }
+
+    fn has_type(&self, kind: &str, extensions: &[&str]) -> bool {
+        if let Some(mime_type) = self.mime_type() {
+            return mime_type.starts_with(kind);
+        }
+        match self.target.extension() {
+            Some(ext) => extensions.contains(&ext.to_string_lossy().to_lowercase().as_str()),
+            None => false,
+        }
+    }
+}
+
+/// An album folder, as created by `AlbumFetcher::create_dir`.
+#[derive(Debug, Clone)]
+pub struct Collection {
+    pub name: String,
+    pub path: PathBuf,
+    pub items: Vec<Item>,
+}
+
+/// What is on disk in an archive: the main library (files at the top of the archive folder)
+/// and the albums (folders at the top of the archive folder). Hidden files and folders are
+/// ignored, as are sidecars.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    pub root: PathBuf,
+    pub library: Vec<Item>,
+    pub albums: Vec<Collection>,
+}
+
+impl Catalog {
+    pub fn scan(root: &Path) -> io::Result<Self> {
+        let root = &root.canonicalize()?;
+        let mut library = Vec::new();
+        let mut albums = Vec::new();
+
+        for entry in sorted_entries(root)? {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().to_string();
+            if path.is_dir() {
+                let mut items = Vec::new();
+                scan_folder(root, &path, &mut items)?;
+                albums.push(Collection {
+                    name,
+                    path: relative(root, &path),
+                    items,
+                });
+            } else if let Some(item) = to_item(root, &path) {
+                library.push(item);
+            }
+        }
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            library,
+            albums,
+        })
+    }
+
+    /// Every file stored in the archive exactly once, i.e. the main library plus the media
+    /// downloaded directly into album folders (shared albums), but not album links.
+    pub fn unique_items(&self) -> Vec<&Item> {
+        self.library
+            .iter()
+            .chain(self.albums.iter().flat_map(|album| album.items.iter()))
+            .filter(|item| !item.is_link)
+            .collect()
+    }
+}
+
+fn sorted_entries(path: &Path) -> io::Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+fn scan_folder(root: &Path, folder: &Path, items: &mut Vec<Item>) -> io::Result<()> {
+    for entry in sorted_entries(folder)? {
+        let path = entry.path();
+        if path.is_dir() {
+            scan_folder(root, &path, items)?;
+        } else if let Some(item) = to_item(root, &path) {
+            items.push(item);
+        }
+    }
+    Ok(())
+}
+
+fn to_item(root: &Path, path: &Path) -> Option<Item> {
+    if Sidecar::is_sidecar(path) {
+        return None;
+    }
+    // Links into the blob store (see `--content-addressed`) are the stored files themselves,
+    // any other link is an album link to a file in the main library.
+    let link_target = fs::read_link(path).ok().map(|target| match path.parent() {
+        Some(parent) if target.is_relative() => normalize(&parent.join(target)),
+        _ => target,
+    });
+    let target = match link_target {
+        Some(target) if !target.starts_with(root.join(BLOBS_FOLDER)) => target,
+        _ => path.to_path_buf(),
+    };
+    let is_link = target != path;
+    // Album links can be named differently from the original (see `--album-order`), so the
+    // sidecar is looked up next to the file they point to.
+    Some(Item {
+        path: relative(root, path),
+        is_link,
+        target: relative(root, &target),
+        sidecar: Sidecar::read(&target),
+    })
+}
+
+fn relative(root: &Path, path: &Path) -> PathBuf {
+    path.strip_prefix(root).unwrap_or(path).to_path_buf()
+}
@@ -1,14 +1,224 @@
 use clap::{clap_app, crate_authors, crate_version};
 
-pub fn cli<'a>() -> clap::ArgMatches<'a> {
+use crate::daemon;
+use crate::list;
+use crate::profile;
+
+fn profile_name(name: String) -> Result<(), String> {
+    profile::validate_name(&name)
+}
+
+fn interval(interval: String) -> Result<(), String> {
+    daemon::parse_interval(&interval).map(|_| ())
+}
+
+fn cron_expression(expression: String) -> Result<(), String> {
+    daemon::parse_cron(&expression).map(|_| ())
+}
+
+fn list_what(what: String) -> Result<(), String> {
+    what.parse::<list::What>().map(|_| ())
+}
+
+/// A command with every backup option (and the `$extra` ones), for the commands that share the
+/// configuration of the backup.
+macro_rules! backup_command {
+    ($name:ident, $about:expr $(, $extra:tt)*) => {
+        clap_app!($name =>
+            (about: $about)
+            (@arg ("BACKUP FOLDER"): "Full path to the destination of the backup folder (defaults to backup_folder in the config file)")
+            (@arg config: --config +takes_value "Config file (defaults to config.toml in the google_photos_backup folder of the user config dir)")
+            (@arg job: --job +takes_value conflicts_with[profile] "Job of the config file with the options to use")
+            (@arg profile: --profile +takes_value {profile_name} "Named profile with its own account, backup folder and options (saved when run with a backup folder)")
+            (@arg client_secret: --("client-secret") +takes_value "OAuth client secret file (client_secret.json) from the Google Cloud console")
+            (@arg token_key_file: --("token-key-file") +takes_value "File with the passphrase of an encrypted token (defaults to the GOOGLE_PHOTOS_BACKUP_TOKEN_PASSPHRASE environment variable)")
+            (@arg shared_albums: --("shared-albums") "Include shared albums when downloading")
+            (@arg no_shared_albums: --("no-shared-albums") conflicts_with[shared_albums] "Don't include shared albums, even if the config file does")
+            (@arg album_filter: -a --("album-filter") +takes_value "Album title filter")
+            (@arg media_filter: -m --("media-filter") +takes_value "Media filename/description filter")
+            (@arg album_order: --("album-order") "Prefix album links with their position in the album")
+            (@arg no_album_order: --("no-album-order") conflicts_with[album_order] "Don't prefix album links with their position, even if the config file does")
+            (@arg album_links: --("album-links") +takes_value possible_value[symlink relative hardlink copy] "How album folders point to the media: symlink (default, hardlink on Windows), relative symlink, hardlink or copy")
+            (@arg sidecars: --("sidecars") "Write a JSON metadata sidecar next to each downloaded media")
+            (@arg no_sidecars: --("no-sidecars") conflicts_with[sidecars] "Don't write sidecars, even if the config file does")
+            (@arg contributor_folders: --("contributor-folders") "Organise shared album media in per-contributor folders")
+            (@arg no_contributor_folders: --("no-contributor-folders") conflicts_with[contributor_folders] "Don't organise shared album media per contributor, even if the config file does")
+            (@arg thumbnails: --thumbnails "Generate thumbnails for each downloaded photo")
+            (@arg no_thumbnails: --("no-thumbnails") conflicts_with[thumbnails] "Don't generate thumbnails, even if the config file does")
+            (@arg s3_bucket: --("s3-bucket") +takes_value "Store the backup in this S3 bucket, under the backup folder as prefix")
+            (@arg s3_endpoint: --("s3-endpoint") +takes_value "S3 compatible endpoint (defaults to AWS)")
+            (@arg s3_region: --("s3-region") +takes_value "S3 region (defaults to us-east-1)")
+            (@arg content_addressed: --("content-addressed") conflicts_with[s3_bucket] "Store each file once in a blob store and link every path to it")
+            (@arg no_content_addressed: --("no-content-addressed") conflicts_with[content_addressed] "Don't use content-addressed storage, even if the config file does")
+            (@arg tar_volumes: --("tar-volumes") +takes_value conflicts_with[s3_bucket content_addressed] "Write the backup into rolling tar volumes of this size (in MiB) instead of a folder tree")
+            (@arg deleted: --deleted +takes_value possible_value[report trash] "What to do with media deleted from Google Photos: report it (default) or move it to .deleted in the backup")
+            (@arg mirror: --mirror conflicts_with[deleted album_filter media_filter] "Show what to remove from the backup to mirror Google Photos (deleted media, stale album links, removed albums)")
+            (@arg no_mirror: --("no-mirror") conflicts_with[mirror] "Don't mirror Google Photos, even if the config file does")
+            (@arg mirror_apply: --("mirror-apply") "Remove what --mirror shows")
+            (@arg no_mirror_apply: --("no-mirror-apply") conflicts_with[mirror_apply] "Don't remove what --mirror shows, even if the config file does")
+            (@arg max_deletions: --("max-deletions") +takes_value "Don't remove anything with --mirror-apply if more than this many files would be removed (defaults to 100)")
+            (@arg encrypt: --encrypt "Encrypt every file written to the backup with a passphrase")
+            (@arg no_encrypt: --("no-encrypt") conflicts_with[encrypt] "Don't encrypt the backup, even if the config file does")
+            (@arg key_file: --("key-file") +takes_value "File with the encryption passphrase (defaults to the GOOGLE_PHOTOS_BACKUP_PASSPHRASE environment variable)")
+            (@arg dry_run: --("dry-run") "List everything and show what would be downloaded, linked and removed, without changing the backup")
+            (@arg report: --report +takes_value possible_value[json] "Print a report of the run in this format at the end (counts, bytes, per album results, errors)")
+            (@arg report_file: --("report-file") +takes_value "Write the report to this file instead of the standard output")
+            $($extra)*
+        )
+    };
+}
+
+/// Commands of the program, anything else on the command line is taken as a backup option.
+const COMMANDS: &[&str] = &[
+    "backup",
+    "list",
+    "status",
+    "verify",
+    "daemon",
+    "retry-failed",
+    "auth",
+    "gallery",
+    "thumbnails",
+    "decrypt",
+    "restore-local",
+    "relink",
+    "jobs",
+    "profiles",
+    "dedup",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
+
+fn app<'a, 'b>() -> clap::App<'a, 'b> {
     clap_app!(
         google_photos_backup =>
             (version: crate_version!())
             (author: crate_authors!())
             (about: "Command line interface to backup your Google Photos")
-            (@arg ("BACKUP FOLDER"): +required "Full path to the destination of the backup folder")
-            (@arg shared_albums: --("shared-albums") "Include shared albums when downloading")
-            (@arg album_filter: -a --("album-filter") +takes_value "Album title filter")
-            (@arg media_filter: -m --("media-filter") +takes_value "Media filename/description filter")
-    ).get_matches()
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand list =>
+                (about: "Prints the metadata of the albums, shared albums or media, without downloading anything")
+                (@arg WHAT: +required {list_what} "What to list: albums, shared-albums or media")
+                (@arg json: --json "Print each one as JSON, as returned by Google Photos")
+                (@arg config: --config +takes_value "Config file (defaults to config.toml in the google_photos_backup folder of the user config dir)")
+                (@arg job: --job +takes_value conflicts_with[profile] "Job of the config file with the options to use")
+                (@arg profile: --profile +takes_value {profile_name} "Named profile of the account to list")
+                (@arg client_secret: --("client-secret") +takes_value "OAuth client secret file (client_secret.json) from the Google Cloud console")
+                (@arg token_key_file: --("token-key-file") +takes_value "File with the passphrase of an encrypted token (defaults to the GOOGLE_PHOTOS_BACKUP_TOKEN_PASSPHRASE environment variable)")
+                (@arg album_filter: -a --("album-filter") +takes_value "Album title filter")
+                (@arg media_filter: -m --("media-filter") +takes_value "Media filename/description filter")
+            )
+            (@subcommand gallery =>
+                (about: "Generates static HTML pages to browse the backup offline")
+                (@arg ("BACKUP FOLDER"): +required "Full path to the backup folder")
+                (@arg output: -o --output +takes_value "Folder for the gallery (defaults to .gallery inside the backup folder)")
+            )
+            (@subcommand thumbnails =>
+                (about: "Generates the missing thumbnails for the photos in the backup")
+                (@arg ("BACKUP FOLDER"): +required "Full path to the backup folder")
+                (@arg force: --force "Rebuild thumbnails that already exist")
+            )
+            (@subcommand decrypt =>
+                (about: "Decrypts an encrypted backup into a plaintext copy")
+                (alias: "restore-local")
+                (@arg ("BACKUP FOLDER"): +required "Full path to the encrypted backup folder")
+                (@arg OUTPUT: +required "Folder for the plaintext copy")
+                (@arg key_file: --("key-file") +takes_value "File with the encryption passphrase (defaults to the GOOGLE_PHOTOS_BACKUP_PASSPHRASE environment variable)")
+            )
+            (@subcommand relink =>
+                (about: "Converts the album links of an existing backup to another link mode")
+                (@arg ("BACKUP FOLDER"): +required "Full path to the backup folder")
+                (@arg album_links: --("album-links") +takes_value +required possible_value[symlink relative hardlink copy] "Link mode to convert the album links to")
+            )
+            (@subcommand auth =>
+                (about: "Manages the authorization to access Google Photos")
+                (@setting SubcommandRequiredElseHelp)
+                (@subcommand login =>
+                    (about: "Authorizes access to Google Photos and stores the token")
+                    (@arg no_browser: --("no-browser") "Authorize from a browser on another machine, pasting the code back here")
+                    (@arg encrypt_token: --("encrypt-token") "Store the token encrypted with a passphrase")
+                    (@arg profile: --profile +takes_value {profile_name} "Named profile to log in to")
+                    (@arg client_secret: --("client-secret") +takes_value "OAuth client secret file (client_secret.json) from the Google Cloud console")
+                    (@arg token_key_file: --("token-key-file") +takes_value "File with the passphrase of an encrypted token (defaults to the GOOGLE_PHOTOS_BACKUP_TOKEN_PASSPHRASE environment variable)")
+                )
+                (@subcommand status =>
+                    (about: "Shows the account, scopes and expiry of the stored token")
+                    (@arg profile: --profile +takes_value {profile_name} "Named profile to show")
+                    (@arg client_secret: --("client-secret") +takes_value "OAuth client secret file (client_secret.json) from the Google Cloud console")
+                    (@arg token_key_file: --("token-key-file") +takes_value "File with the passphrase of an encrypted token (defaults to the GOOGLE_PHOTOS_BACKUP_TOKEN_PASSPHRASE environment variable)")
+                )
+                (@subcommand logout =>
+                    (about: "Deletes the stored token")
+                    (@arg profile: --profile +takes_value {profile_name} "Named profile to log out of")
+                )
+                (@subcommand revoke =>
+                    (about: "Revokes the access given to google-photos-backup and deletes the stored token")
+                    (@arg profile: --profile +takes_value {profile_name} "Named profile to revoke")
+                    (@arg token_key_file: --("token-key-file") +takes_value "File with the passphrase of an encrypted token (defaults to the GOOGLE_PHOTOS_BACKUP_TOKEN_PASSPHRASE environment variable)")
+                )
+            )
+            (@subcommand jobs =>
+                (about: "Lists the jobs of the config file, or runs every one of them with --run")
+                (@arg config: --config +takes_value "Config file (defaults to config.toml in the google_photos_backup folder of the user config dir)")
+                (@arg run: --run "Run every job, one after the other")
+            )
+            (@subcommand profiles =>
+                (about: "Lists the saved profiles, or backs up every one of them with --run")
+                (@arg run: --run "Back up every profile, one after the other")
+            )
+            (@subcommand dedup =>
+                (about: "Converts the backup to content-addressed storage, removing duplicate files")
+                (@arg ("BACKUP FOLDER"): +required "Full path to the backup folder")
+                (@arg album_links: --("album-links") +takes_value possible_value[symlink relative hardlink] "How each file points to its blob: symlink (default, hardlink on Windows), relative symlink or hardlink")
+            )
+    )
+    .subcommand(backup_command!(
+        backup,
+        "Backs up Google Photos (the default command)"
+    ))
+    .subcommand(backup_command!(
+        daemon,
+        "Keeps running and backs up on a schedule, reloading the config file on SIGHUP",
+        (@arg interval: --interval +takes_value {interval} conflicts_with[cron] "Time between the start of two backups, e.g. 30m, 6h or 1d"),
+        (@arg cron: --cron +takes_value {cron_expression} "Cron expression of when to back up, e.g. \"30 3 * * *\" for every night at 3:30 (UTC)")
+    ))
+    .subcommand(
+        backup_command!(
+            retry_failed,
+            "Downloads again, with fresh download URLs, the media that failed in previous backups"
+        )
+        .name("retry-failed"),
+    )
+    .subcommand(backup_command!(
+        status,
+        "Shows the state of the backup, from what previous backups stored"
+    ))
+    .subcommand(backup_command!(
+        verify,
+        "Checks that the media stored by previous backups is in the backup, and that no link is broken"
+    ))
+}
+
+/// The command line, with the `backup` command added when there is none so that the backup
+/// options can still be given on their own.
+pub fn command_line() -> Vec<String> {
+    let mut args = std::env::args().collect::<Vec<_>>();
+    if let Some(arg) = args.get(1) {
+        if !COMMANDS.contains(&arg.as_str()) {
+            args.insert(1, "backup".to_string());
+        }
+    }
+    args
+}
+
+pub fn cli<'a>() -> clap::ArgMatches<'a> {
+    app().get_matches_from(command_line())
+}
+
+/// Parses `args` (starting with the program name) instead of the command line.
+pub fn cli_from<'a>(args: &[String]) -> clap::Result<clap::ArgMatches<'a>> {
+    app().get_matches_from_safe(args)
 }
@@ -1,70 +1,412 @@
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use clap::value_t;
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
-use crate::filesystem::ensure_folder;
+use crate::config_file::{self, ConfigFile};
+use crate::daemon::{self, Schedule};
+use crate::filesystem::{ensure_folder, LinkMode};
+
+const DEFAULT_S3_ENDPOINT: &str = "https://s3.amazonaws.com";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_MAX_DELETIONS: usize = 100;
+
+#[derive(Debug, Clone)]
+pub struct S3Options {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+}
+
+/// What to do with media in the archive that was deleted from Google Photos.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeletedPolicy {
+    Report,
+    Trash,
+}
+
+impl FromStr for DeletedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(DeletedPolicy::Report),
+            "trash" => Ok(DeletedPolicy::Trash),
+            _ => Err(format!("unknown policy for deleted media: {}", s)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct CliOptions {
+    pub client_secret: Option<PathBuf>,
+    /// File with the passphrase of an encrypted token, instead of the environment variable.
+    pub token_key_file: Option<PathBuf>,
     pub shared_albums: bool,
     pub album_filter: Option<Regex>,
     pub media_filter: Option<Regex>,
+    pub album_order: bool,
+    pub album_links: LinkMode,
+    pub sidecars: bool,
+    pub contributor_folders: bool,
+    pub thumbnails: bool,
+    pub s3: Option<S3Options>,
+    pub content_addressed: bool,
+    /// Maximum size of a tar volume in bytes, when writing tar volumes.
+    pub volume_size: Option<u64>,
+    pub encrypt: bool,
+    /// File with the encryption passphrase, instead of the environment variable.
+    pub key_file: Option<PathBuf>,
+    pub deleted: DeletedPolicy,
+    pub mirror: bool,
+    pub mirror_apply: bool,
+    pub max_deletions: usize,
+    /// Only report what a backup would do, without writing to the archive.
+    pub dry_run: bool,
+    /// When the daemon backs up.
+    pub schedule: Option<Schedule>,
+    /// Whether to write the JSON report of the run.
+    pub report: bool,
+    /// File for the report, instead of the standard output.
+    pub report_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    profile: Option<String>,
     cache_dir: PathBuf,
-    archive_dir: PathBuf,
+    /// Not needed by the commands that only talk to Google Photos.
+    archive_dir: Option<PathBuf>,
     options: CliOptions,
 }
 
+/// The value of a key of the config file, checked when loading it but parsed again here.
+fn parse_file_value<T>(key: &str, value: Option<String>) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: ToString,
+{
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e: T::Err| format!("{}: {}", key, e.to_string()))
+        })
+        .transpose()
+}
+
 impl Config {
-    pub fn new<'a>(args: &clap::ArgMatches<'a>) -> Self {
-        let archive_dir = PathBuf::from(args.value_of("BACKUP FOLDER").unwrap());
-        let mut cache_dir = ::dirs::cache_dir().expect("Could not get cache dir");
-        cache_dir.push("google_photos_backup");
-        ensure_folder(&cache_dir);
+    /// The options from the command line, falling back to the ones in the config file (of the
+    /// job, if one is given). A backup folder is required.
+    pub fn new<'a>(args: &clap::ArgMatches<'a>) -> Result<Self, String> {
+        Self::load(args, true)
+    }
+
+    /// The options of the commands that only talk to Google Photos, without a backup folder.
+    pub fn for_account<'a>(args: &clap::ArgMatches<'a>) -> Result<Self, String> {
+        Self::load(args, false)
+    }
+
+    fn load<'a>(args: &clap::ArgMatches<'a>, needs_archive: bool) -> Result<Self, String> {
+        let file = ConfigFile::load(args.value_of("config").map(Path::new))
+            .and_then(|file| file.options(args.value_of("job")))?;
+        // `--no-<flag>` turns off a flag set in the config file.
+        let flag = |name: &str, value: Option<bool>| {
+            !args.is_present(format!("no_{}", name))
+                && (args.is_present(name) || value == Some(true))
+        };
+
+        let archive_dir = value_t!(args, "BACKUP FOLDER", PathBuf)
+            .ok()
+            .or(file.backup_folder);
+        if needs_archive && archive_dir.is_none() {
+            return Err(
+                "no backup folder given, as argument or as backup_folder in the config file"
+                    .to_string(),
+            );
+        }
+        let profile = value_t!(args, "profile", String).ok().or(file.profile);
+        let cache_dir = Config::cache_for(profile.as_deref());
+
+        let client_secret = value_t!(args, "client_secret", PathBuf)
+            .ok()
+            .or(file.client_secret);
+        let token_key_file = value_t!(args, "token_key_file", PathBuf)
+            .ok()
+            .or(file.token_key_file);
+        let shared_albums = flag("shared_albums", file.shared_albums);
+        let album_filter = match value_t!(args, "album_filter", Regex).ok() {
+            Some(filter) => Some(filter),
+            None => parse_file_value("album_filter", file.album_filter)?,
+        };
+        let media_filter = match value_t!(args, "media_filter", Regex).ok() {
+            Some(filter) => Some(filter),
+            None => parse_file_value("media_filter", file.media_filter)?,
+        };
+        let album_order = flag("album_order", file.album_order);
+        let album_links = match value_t!(args, "album_links", LinkMode).ok() {
+            Some(album_links) => Some(album_links),
+            None => parse_file_value("album_links", file.album_links)?,
+        }
+        .unwrap_or_default();
+        let sidecars = flag("sidecars", file.sidecars);
+        let contributor_folders = flag("contributor_folders", file.contributor_folders);
+        let thumbnails = flag("thumbnails", file.thumbnails);
+        let content_addressed = flag("content_addressed", file.content_addressed);
+        let deleted = match value_t!(args, "deleted", DeletedPolicy).ok() {
+            Some(deleted) => Some(deleted),
+            None => parse_file_value("deleted", file.deleted)?,
+        };
+        let mirror = flag("mirror", file.mirror);
+        let mirror_apply = flag("mirror_apply", file.mirror_apply);
+        let max_deletions = if args.is_present("max_deletions") {
+            Some(value_t!(args, "max_deletions", usize).map_err(|e| e.message)?)
+        } else {
+            file.max_deletions
+        };
+        let encrypt = flag("encrypt", file.encrypt);
+        let key_file = value_t!(args, "key_file", PathBuf).ok().or(file.key_file);
+        let tar_volumes = if args.is_present("tar_volumes") {
+            Some(value_t!(args, "tar_volumes", u64).map_err(|e| e.message)?)
+        } else {
+            file.tar_volumes
+        };
+        let volume_size = match tar_volumes {
+            Some(0) => {
+                return Err(
+                    "tar_volumes: the size of the tar volumes must be at least 1 MiB".to_string(),
+                )
+            }
+            tar_volumes => tar_volumes.map(|size| size * 1024 * 1024),
+        };
+        let dry_run = args.is_present("dry_run");
+        let report = args.is_present("report") || file.report.is_some();
+        let report_file = value_t!(args, "report_file", PathBuf)
+            .ok()
+            .or(file.report_file);
+        // The config file only sets the schedule when the command line doesn't.
+        let schedule = match (
+            value_t!(args, "interval", String).ok(),
+            value_t!(args, "cron", String).ok(),
+        ) {
+            (None, None) => (file.interval, file.cron),
+            schedule => schedule,
+        };
+        let schedule = match schedule {
+            (Some(interval), _) => Some(Schedule::Interval(
+                daemon::parse_interval(&interval).map_err(|e| format!("interval: {}", e))?,
+            )),
+            (None, Some(cron)) => Some(Schedule::Cron(Box::new(
+                daemon::parse_cron(&cron).map_err(|e| format!("cron: {}", e))?,
+            ))),
+            (None, None) => None,
+        };
+        let s3_bucket = value_t!(args, "s3_bucket", String).ok().or(file.s3_bucket);
+        let s3_endpoint = value_t!(args, "s3_endpoint", String)
+            .ok()
+            .or(file.s3_endpoint);
+        let s3_region = value_t!(args, "s3_region", String).ok().or(file.s3_region);
+
+        // The command line and the config file are each checked on their own, but an option
+        // of one can still conflict with, or need, an option of the other.
+        config_file::check_combination(|key| match key {
+            "s3_bucket" => s3_bucket.is_some(),
+            "s3_endpoint" => s3_endpoint.is_some(),
+            "s3_region" => s3_region.is_some(),
+            "content_addressed" => content_addressed,
+            "tar_volumes" => volume_size.is_some(),
+            "deleted" => deleted.is_some(),
+            "mirror" => mirror,
+            "mirror_apply" => mirror_apply,
+            "max_deletions" => max_deletions.is_some(),
+            "album_filter" => album_filter.is_some(),
+            "media_filter" => media_filter.is_some(),
+            "encrypt" => encrypt,
+            "key_file" => key_file.is_some(),
+            "report" => report,
+            "report_file" => report_file.is_some(),
+            // Only one of them is taken from the command line or the file.
+            _ => false,
+        })?;
+
+        // The blobs are the only copy of the content, which a copy would duplicate.
+        if content_addressed && album_links == LinkMode::Copy {
+            return Err("content_addressed can't be used with album_links = \"copy\"".to_string());
+        }
 
-        let shared_albums = args.is_present("shared_albums");
-        let album_filter = value_t!(args, "album_filter", Regex).ok();
-        let media_filter = value_t!(args, "media_filter", Regex).ok();
+        let s3 = s3_bucket.map(|bucket| S3Options {
+            endpoint: s3_endpoint.unwrap_or_else(|| DEFAULT_S3_ENDPOINT.to_string()),
+            region: s3_region.unwrap_or_else(|| DEFAULT_S3_REGION.to_string()),
+            bucket,
+        });
 
-        Self {
+        // With object storage the backup folder is the prefix of the keys in the bucket, so
+        // there's nothing to create locally. A dry run doesn't create it either, nor do the
+        // commands that don't use it.
+        let archive_dir = match archive_dir {
+            _ if !needs_archive => None,
+            Some(archive_dir) if s3.is_none() && dry_run => {
+                Some(archive_dir.canonicalize().unwrap_or(archive_dir))
+            }
+            Some(archive_dir) if s3.is_none() => {
+                Some(Config::discover_archive_fullpath(&archive_dir)?)
+            }
+            archive_dir => archive_dir,
+        };
+
+        Ok(Self {
+            profile,
             cache_dir,
-            archive_dir: Config::discover_archive_fullpath(&archive_dir),
+            archive_dir,
             options: CliOptions {
+                client_secret,
+                token_key_file,
                 shared_albums,
                 album_filter,
                 media_filter,
+                album_order,
+                album_links,
+                sidecars,
+                contributor_folders,
+                thumbnails,
+                s3,
+                content_addressed,
+                volume_size,
+                encrypt,
+                key_file,
+                deleted: deleted.unwrap_or(DeletedPolicy::Report),
+                mirror,
+                mirror_apply,
+                max_deletions: max_deletions.unwrap_or(DEFAULT_MAX_DELETIONS),
+                dry_run,
+                schedule,
+                report,
+                report_file,
             },
-        }
+        })
     }
 
-    fn discover_archive_fullpath(basepath: &Path) -> PathBuf {
+    fn discover_archive_fullpath(basepath: &Path) -> Result<PathBuf, String> {
         match basepath.canonicalize() {
-            Ok(path) => path,
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::NotFound => {
-                    ensure_folder(basepath);
-                    basepath.to_path_buf()
-                }
-                e => {
-                    panic!("Can't deal with unknown path: {:?}", e);
-                }
-            },
+            Ok(path) => Ok(path),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir_all(basepath).map_err(|e| {
+                    format!("could not create the backup folder {:?}: {}", basepath, e)
+                })?;
+                Ok(basepath.to_path_buf())
+            }
+            Err(e) => Err(format!("invalid backup folder {:?}: {}", basepath, e)),
+        }
+    }
+
+    /// Cache folder (with the token and state) of `profile`, or of the default account.
+    pub fn cache_for(profile: Option<&str>) -> PathBuf {
+        let mut cache_dir = ::dirs::cache_dir().expect("Could not get cache dir");
+        cache_dir.push("google_photos_backup");
+        if let Some(profile) = profile {
+            // Each profile has its own token and state.
+            cache_dir.push("profiles");
+            cache_dir.push(profile);
         }
+        ensure_folder(&cache_dir);
+        cache_dir
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
     }
 
     pub fn cache(&self) -> PathBuf {
         self.cache_dir.clone()
     }
 
+    /// The backup folder, which only configs made with `for_account` don't have.
     pub fn archive(&self) -> PathBuf {
-        self.archive_dir.clone()
+        self.archive_dir
+            .clone()
+            .expect("the backup folder is checked when making the config")
+    }
+
+    /// Where the archive is: its folder, or its URL in object storage.
+    pub fn location(&self) -> String {
+        match &self.options.s3 {
+            Some(s3) => format!("s3://{}/{}", s3.bucket, self.archive().display()),
+            None => self.archive().display().to_string(),
+        }
+    }
+
+    /// Folder in the cache for the state kept about this archive between runs.
+    pub fn state_dir(&self) -> PathBuf {
+        let key = hex::encode(Sha256::digest(self.location().as_bytes()));
+        let state_dir = self.cache_dir.join("archives").join(&key[..16]);
+        ensure_folder(&state_dir);
+        state_dir
     }
 
     pub fn options(&self) -> CliOptions {
         self.options.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The options of a backup with `args` and the config file `contents`.
+    fn load(contents: &str, args: &[&str]) -> Result<CliOptions, String> {
+        let path = std::env::temp_dir().join(format!("gpb-load-{}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let mut command_line = vec![
+            "google-photos-backup".to_string(),
+            "backup".to_string(),
+            format!("--config={}", path.display()),
+        ];
+        command_line.extend(args.iter().map(|arg| arg.to_string()));
+        let matches = crate::cli::cli_from(&command_line).unwrap();
+        let options = Config::for_account(matches.subcommand_matches("backup").unwrap())
+            .map(|config| config.options());
+        std::fs::remove_file(&path).unwrap();
+        options
+    }
+
+    #[test]
+    fn test_command_line_and_file_combination() {
+        let error = load("s3_bucket = \"photos\"", &["--content-addressed"]).unwrap_err();
+        assert_eq!(error, "s3_bucket can't be used with content_addressed");
+        let error = load("", &["--mirror-apply"]).unwrap_err();
+        assert_eq!(error, "mirror_apply requires mirror to be set");
+        let error = load("album_links = \"copy\"", &["--content-addressed"]).unwrap_err();
+        assert_eq!(
+            error,
+            "content_addressed can't be used with album_links = \"copy\""
+        );
+        let error = load("encrypt = true\nkey_file = \"key\"", &["--no-encrypt"]).unwrap_err();
+        assert_eq!(error, "key_file requires encrypt to be set");
+
+        let options = load("mirror = true", &["--mirror-apply"]).unwrap();
+        assert!(options.mirror && options.mirror_apply);
+    }
+
+    #[test]
+    fn test_no_flags() {
+        let options = load("sidecars = true\nthumbnails = true", &["--no-sidecars"]).unwrap();
+        assert!(!options.sidecars);
+        assert!(options.thumbnails);
+    }
+
+    #[test]
+    fn test_report() {
+        let options = load("report = \"json\"\nreport_file = \"report.json\"", &[]).unwrap();
+        assert!(options.report);
+        assert_eq!(options.report_file, Some(PathBuf::from("report.json")));
+        let error = load("report = \"xml\"", &[]).unwrap_err();
+        assert!(
+            error.ends_with("report: unknown report format: xml"),
+            "{}",
+            error
+        );
+        assert!(!load("", &[]).unwrap().report);
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use toml::Value;
+
+use crate::config::DeletedPolicy;
+use crate::daemon;
+use crate::filesystem::LinkMode;
+use crate::profile;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Options that can be set in the config file, named after the command line options (with
+/// `_` instead of `-`). Options set at the top level apply to every job, unless the job sets
+/// them too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileOptions {
+    pub backup_folder: Option<PathBuf>,
+    pub profile: Option<String>,
+    pub client_secret: Option<PathBuf>,
+    pub token_key_file: Option<PathBuf>,
+    pub shared_albums: Option<bool>,
+    pub album_filter: Option<String>,
+    pub media_filter: Option<String>,
+    pub album_order: Option<bool>,
+    pub album_links: Option<String>,
+    pub sidecars: Option<bool>,
+    pub contributor_folders: Option<bool>,
+    pub thumbnails: Option<bool>,
+    pub s3_bucket: Option<String>,
+    pub s3_endpoint: Option<String>,
+    pub s3_region: Option<String>,
+    pub content_addressed: Option<bool>,
+    pub tar_volumes: Option<u64>,
+    pub deleted: Option<String>,
+    pub mirror: Option<bool>,
+    pub mirror_apply: Option<bool>,
+    pub max_deletions: Option<usize>,
+    pub encrypt: Option<bool>,
+    pub key_file: Option<PathBuf>,
+    pub interval: Option<String>,
+    pub cron: Option<String>,
+    pub report: Option<String>,
+    pub report_file: Option<PathBuf>,
+}
+
+/// Options that can't be used together, and options that need another one.
+const CONFLICTS: &[(&str, &str)] = &[
+    ("s3_bucket", "content_addressed"),
+    ("s3_bucket", "tar_volumes"),
+    ("content_addressed", "tar_volumes"),
+    ("mirror", "deleted"),
+    ("mirror", "album_filter"),
+    ("mirror", "media_filter"),
+    ("interval", "cron"),
+];
+const REQUIRES: &[(&str, &str)] = &[
+    ("s3_endpoint", "s3_bucket"),
+    ("s3_region", "s3_bucket"),
+    ("mirror_apply", "mirror"),
+    ("max_deletions", "mirror"),
+    ("key_file", "encrypt"),
+    ("report_file", "report"),
+];
+
+/// Checks that no two options of `CONFLICTS` are `set`, and that the options of `REQUIRES` are
+/// only `set` with the one they need.
+pub fn check_combination(set: impl Fn(&str) -> bool) -> Result<(), String> {
+    for (a, b) in CONFLICTS {
+        if set(a) && set(b) {
+            return Err(format!("{} can't be used with {}", a, b));
+        }
+    }
+    for (a, b) in REQUIRES {
+        if set(a) && !set(b) {
+            return Err(format!("{} requires {} to be set", a, b));
+        }
+    }
+    Ok(())
+}
+
+impl FileOptions {
+    /// These options, with the ones they don't set taken from `defaults`.
+    fn or(self, defaults: &FileOptions) -> FileOptions {
+        let defaults = defaults.clone();
+        FileOptions {
+            backup_folder: self.backup_folder.or(defaults.backup_folder),
+            profile: self.profile.or(defaults.profile),
+            client_secret: self.client_secret.or(defaults.client_secret),
+            token_key_file: self.token_key_file.or(defaults.token_key_file),
+            shared_albums: self.shared_albums.or(defaults.shared_albums),
+            album_filter: self.album_filter.or(defaults.album_filter),
+            media_filter: self.media_filter.or(defaults.media_filter),
+            album_order: self.album_order.or(defaults.album_order),
+            album_links: self.album_links.or(defaults.album_links),
+            sidecars: self.sidecars.or(defaults.sidecars),
+            contributor_folders: self.contributor_folders.or(defaults.contributor_folders),
+            thumbnails: self.thumbnails.or(defaults.thumbnails),
+            s3_bucket: self.s3_bucket.or(defaults.s3_bucket),
+            s3_endpoint: self.s3_endpoint.or(defaults.s3_endpoint),
+            s3_region: self.s3_region.or(defaults.s3_region),
+            content_addressed: self.content_addressed.or(defaults.content_addressed),
+            tar_volumes: self.tar_volumes.or(defaults.tar_volumes),
+            deleted: self.deleted.or(defaults.deleted),
+            mirror: self.mirror.or(defaults.mirror),
+            mirror_apply: self.mirror_apply.or(defaults.mirror_apply),
+            max_deletions: self.max_deletions.or(defaults.max_deletions),
+            encrypt: self.encrypt.or(defaults.encrypt),
+            key_file: self.key_file.or(defaults.key_file),
+            interval: self.interval.or(defaults.interval),
+            cron: self.cron.or(defaults.cron),
+            report: self.report.or(defaults.report),
+            report_file: self.report_file.or(defaults.report_file),
+        }
+    }
+
+    /// Names of the options set, `false` flags counting as not set.
+    fn keys(&self) -> Vec<String> {
+        match Value::try_from(self) {
+            Ok(Value::Table(table)) => table
+                .into_iter()
+                .filter(|(_, value)| value.as_bool() != Some(false))
+                .map(|(key, _)| key)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks the values that can't be checked by their type. `section` is the prefix of the
+    /// keys in the file (e.g. `jobs.photos.`).
+    fn validate(&self, section: &str) -> Result<(), String> {
+        let invalid = |key: &str, e: String| Err(format!("{}{}: {}", section, key, e));
+        if let Some(name) = &self.profile {
+            if let Err(e) = profile::validate_name(name) {
+                return invalid("profile", e);
+            }
+        }
+        for (key, filter) in &[
+            ("album_filter", &self.album_filter),
+            ("media_filter", &self.media_filter),
+        ] {
+            if let Some(Err(e)) = filter.as_deref().map(Regex::new) {
+                return invalid(key, e.to_string());
+            }
+        }
+        if let Some(Err(e)) = self.album_links.as_deref().map(str::parse::<LinkMode>) {
+            return invalid("album_links", e);
+        }
+        if let Some(Err(e)) = self.deleted.as_deref().map(str::parse::<DeletedPolicy>) {
+            return invalid("deleted", e);
+        }
+        if let Some(Err(e)) = self.interval.as_deref().map(daemon::parse_interval) {
+            return invalid("interval", e);
+        }
+        if let Some(Err(e)) = self.cron.as_deref().map(daemon::parse_cron) {
+            return invalid("cron", e);
+        }
+        if let Some(format) = self.report.as_deref().filter(|format| *format != "json") {
+            return invalid("report", format!("unknown report format: {}", format));
+        }
+        if self.tar_volumes == Some(0) {
+            return invalid("tar_volumes", "must be at least 1 (MiB)".to_string());
+        }
+        Ok(())
+    }
+
+    /// Checks the options of a job, once merged with the top level ones.
+    fn validate_combination(&self) -> Result<(), String> {
+        let keys = self.keys();
+        check_combination(|key| keys.iter().any(|set| set == key))
+    }
+}
+
+/// The config file, with options for every backup and named jobs (e.g. with different
+/// filters and backup folders).
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    path: Option<PathBuf>,
+    defaults: FileOptions,
+    jobs: BTreeMap<String, FileOptions>,
+}
+
+/// `config.toml` in the user config dir.
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("google_photos_backup").join(CONFIG_FILE))
+}
+
+fn parse_options(value: Value, section: &str) -> Result<FileOptions, String> {
+    let options: FileOptions = value.try_into().map_err(|e| {
+        if section.is_empty() {
+            e.to_string()
+        } else {
+            format!("[{}]: {}", section.trim_end_matches('.'), e)
+        }
+    })?;
+    options.validate(section)?;
+    Ok(options)
+}
+
+impl ConfigFile {
+    /// Loads the config file at `path`, or the default config file (if there is one) without
+    /// a path.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_path().filter(|path| path.exists()) {
+                Some(path) => path,
+                None => return Ok(ConfigFile::default()),
+            },
+        };
+        Self::parse(&path).map_err(|e| format!("Invalid config file {:?}: {}", path, e))
+    }
+
+    fn parse(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut table: toml::value::Table = toml::from_str(&contents).map_err(|e| e.to_string())?;
+        let mut jobs = BTreeMap::new();
+        match table.remove("jobs") {
+            Some(Value::Table(tables)) => {
+                for (name, value) in tables {
+                    let section = format!("jobs.{}.", name);
+                    jobs.insert(name, parse_options(value, &section)?);
+                }
+            }
+            Some(_) => return Err("jobs: expected a table of jobs".to_string()),
+            None => (),
+        }
+        let defaults = parse_options(Value::Table(table), "")?;
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            defaults,
+            jobs,
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn jobs(&self) -> impl Iterator<Item = (&String, FileOptions)> {
+        self.jobs
+            .iter()
+            .map(move |(name, job)| (name, job.clone().or(&self.defaults)))
+    }
+
+    /// Options of `job`, or the top level options without one.
+    pub fn options(&self, job: Option<&str>) -> Result<FileOptions, String> {
+        let path = self
+            .path
+            .as_deref()
+            .unwrap_or_else(|| Path::new(CONFIG_FILE));
+        let options = match job {
+            Some(name) => match self.jobs.get(name) {
+                Some(job) => job.clone().or(&self.defaults),
+                None => return Err(format!("No job {:?} in the config file {:?}", name, path)),
+            },
+            None => self.defaults.clone(),
+        };
+        options.validate_combination().map_err(|e| match job {
+            Some(name) => format!("Invalid config file {:?}: job {}: {}", path, name, e),
+            None => format!("Invalid config file {:?}: {}", path, e),
+        })?;
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<ConfigFile, String> {
+        let path = std::env::temp_dir().join(format!("gpb-config-{}.toml", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let config = ConfigFile::parse(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_job_options() {
+        let config = parse(
+            r#"
+            shared_albums = true
+            album_links = "relative"
+
+            [jobs.videos]
+            backup_folder = "/backups/videos"
+            media_filter = "\\.mp4$"
+            shared_albums = false
+            "#,
+        )
+        .unwrap();
+        let videos = config.options(Some("videos")).unwrap();
+        assert_eq!(videos.shared_albums, Some(false));
+        assert_eq!(videos.album_links.as_deref(), Some("relative"));
+        assert_eq!(videos.media_filter.as_deref(), Some("\\.mp4$"));
+        assert!(config.options(Some("photos")).is_err());
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let error = parse("[jobs.videos]\nmedia_filter = \"(\"").unwrap_err();
+        assert!(error.starts_with("jobs.videos.media_filter: "), "{}", error);
+        let error = parse("[jobs.videos]\nshared_album = true").unwrap_err();
+        assert!(error.contains("shared_album"), "{}", error);
+        let error = parse("mirror = true\n[jobs.videos]\nalbum_filter = \"a\"")
+            .unwrap()
+            .options(Some("videos"))
+            .unwrap_err();
+        assert!(error.ends_with("job videos: mirror can't be used with album_filter"));
+    }
+}
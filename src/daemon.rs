@@ -0,0 +1,116 @@
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use signal_hook::consts::SIGHUP;
+
+use crate::shutdown;
+
+/// When the daemon runs a backup.
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// A backup this long after the previous one started.
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+/// A duration like `90s`, `30m`, `6h` or `1d`.
+pub fn parse_interval(interval: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "invalid interval {:?}, use a number followed by s, m, h or d (e.g. 6h)",
+            interval
+        )
+    };
+    let unit = interval.chars().last().ok_or_else(invalid)?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    match interval[..interval.len() - unit.len_utf8()].parse::<u64>() {
+        Ok(count) if count > 0 => count
+            .checked_mul(seconds)
+            .map(Duration::from_secs)
+            .ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// A cron expression, with the usual 5 fields (minute to day of week) or with seconds first.
+pub fn parse_cron(expression: &str) -> Result<cron::Schedule, String> {
+    let expression = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+    cron::Schedule::from_str(&expression)
+        .map_err(|e| format!("invalid cron expression {:?}: {}", expression, e))
+}
+
+impl Schedule {
+    /// When the backup after the one started at `started` should start, which is now if
+    /// that time has passed already.
+    pub fn next(&self, started: DateTime<Utc>) -> DateTime<Utc> {
+        let now = Utc::now();
+        let next = match self {
+            Schedule::Interval(interval) => chrono::Duration::from_std(*interval)
+                .ok()
+                .and_then(|interval| started.checked_add_signed(interval))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            // Runs missed while the backup was running are skipped.
+            Schedule::Cron(schedule) => schedule
+                .after(&now)
+                .next()
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        };
+        std::cmp::max(next, now)
+    }
+}
+
+/// Flag set when the process gets a SIGHUP, to reload the configuration.
+pub fn reload_flag() -> io::Result<Arc<AtomicBool>> {
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, reload.clone())?;
+    Ok(reload)
+}
+
+/// Sleeps until `time`, or until `wake` is set or a shutdown requested. Returns whether `wake`
+/// was set (and clears it).
+pub fn sleep_until(time: DateTime<Utc>, wake: &AtomicBool) -> bool {
+    while Utc::now() < time && !shutdown::requested() {
+        if wake.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    wake.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("6h"), Ok(Duration::from_secs(6 * 3600)));
+        assert_eq!(parse_interval("1d"), Ok(Duration::from_secs(86400)));
+        assert!(parse_interval("0h").is_err());
+        assert!(parse_interval("h").is_err());
+        assert!(parse_interval("6").is_err());
+        assert!(parse_interval("").is_err());
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        assert!(parse_cron("30 3 * * *").is_ok());
+        assert!(parse_cron("0 30 3 * * *").is_ok());
+        assert!(parse_cron("every night").is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::catalog::Catalog;
+use crate::filesystem::{self, LinkMode};
+use crate::manifest::Manifest;
+use crate::storage::{LocalStorage, Storage};
+
+/// The blob store is a hidden folder inside the archive, with blobs named after the SHA-256
+/// of their content (keyed with HMAC in encrypted backups) and sharded by the first two
+/// characters of the hash.
+pub const BLOBS_FOLDER: &str = ".blobs";
+
+static STAGED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn blob_path(hash: &str) -> PathBuf {
+    Path::new(BLOBS_FOLDER).join(&hash[..2]).join(hash)
+}
+
+/// Link to the stored media with this id, so media in several places (e.g. a shared album
+/// with media from the library) is only downloaded once.
+pub fn id_path(id: &str) -> PathBuf {
+    Path::new(BLOBS_FOLDER).join("ids").join(id)
+}
+
+/// Hashes the content of a file into the name of its blob.
+#[derive(Clone)]
+enum Hasher {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl Hasher {
+    fn new(key: Option<&[u8]>) -> Self {
+        match key {
+            Some(key) => {
+                Hasher::Keyed(Hmac::new_varkey(key).expect("HMAC accepts keys of any size"))
+            }
+            None => Hasher::Plain(Sha256::new()),
+        }
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Plain(hasher) => hasher.input(data),
+            Hasher::Keyed(mac) => mac.input(data),
+        }
+    }
+
+    fn hash(self) -> String {
+        match self {
+            Hasher::Plain(hasher) => hex::encode(hasher.result()),
+            Hasher::Keyed(mac) => hex::encode(mac.result().code()),
+        }
+    }
+}
+
+struct HashingReader {
+    inner: Box<dyn Read + Send>,
+    hasher: Arc<Mutex<Hasher>>,
+}
+
+impl Read for HashingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.lock().unwrap().input(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Storage where every file is stored once in the blob store and every path in the archive
+/// is a link to its blob.
+pub struct ContentAddressedStorage {
+    inner: Arc<dyn Storage>,
+    /// Key of the blob names, when the content is encrypted.
+    key: Option<Vec<u8>>,
+    /// The staged files of the writes in progress, not moved into the blob store yet.
+    staged: Mutex<HashSet<PathBuf>>,
+}
+
+impl ContentAddressedStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> Self {
+        Self::with_key(inner, None)
+    }
+
+    /// Storage with the blobs named after the HMAC of their content with `key`, if any.
+    pub fn with_key(inner: Arc<dyn Storage>, key: Option<Vec<u8>>) -> Self {
+        Self {
+            inner,
+            key,
+            staged: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn staged_path() -> PathBuf {
+        let count = STAGED_COUNT.fetch_add(1, Ordering::SeqCst);
+        Path::new(BLOBS_FOLDER)
+            .join("staged")
+            .join(format!("{}-{}", std::process::id(), count))
+    }
+
+    /// Moves the file at `staged`, with content hashing to `hash`, into the blob store (or
+    /// drops it if the blob is already there) and links `path` to the blob. Returns whether
+    /// the content was a duplicate.
+    fn store(&self, staged: &Path, hash: &str, path: &Path) -> io::Result<bool> {
+        let blob = blob_path(hash);
+        let duplicate = self.inner.exists(&blob);
+        if duplicate {
+            self.inner.remove(staged)?;
+        } else {
+            self.inner.rename(staged, &blob)?;
+        }
+        let _ = self.inner.remove(path);
+        self.inner.link(&blob, path)?;
+        debug!("Stored {:?} as {:?} (duplicate: {})", path, blob, duplicate);
+        Ok(duplicate)
+    }
+
+    /// Moves a file that is already in the archive into the blob store. Returns whether the
+    /// content was a duplicate and the size of the file, or nothing if the file already is a
+    /// hard link to its blob.
+    pub fn adopt(&self, path: &Path) -> io::Result<Option<(bool, u64)>> {
+        let root = self
+            .inner
+            .local_root()
+            .ok_or_else(|| io::Error::other("only files in a local archive can be deduplicated"))?;
+        let mut file = File::open(root.join(path))?;
+        let mut hasher = Hasher::new(self.key.as_deref());
+        let mut buffer = [0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.input(&buffer[..read]);
+            size += read as u64;
+        }
+        let hash = hasher.hash();
+        if filesystem::same_file(&root.join(blob_path(&hash)), &root.join(path)).unwrap_or(false) {
+            return Ok(None);
+        }
+        let duplicate = self.store(path, &hash, path)?;
+        Ok(Some((duplicate, size)))
+    }
+}
+
+impl Storage for ContentAddressedStorage {
+    fn put(&self, path: &Path, reader: Box<dyn Read + Send>, size: Option<u64>) -> io::Result<u64> {
+        let hasher = Arc::new(Mutex::new(Hasher::new(self.key.as_deref())));
+        let reader = HashingReader {
+            inner: reader,
+            hasher: hasher.clone(),
+        };
+        let staged = Self::staged_path();
+        self.staged.lock().unwrap().insert(staged.clone());
+        let stored = self
+            .inner
+            .put(&staged, Box::new(reader), size)
+            .and_then(|written| {
+                let hash = hasher.lock().unwrap().clone().hash();
+                self.store(&staged, &hash, path)?;
+                Ok(written)
+            });
+        self.staged.lock().unwrap().remove(&staged);
+        stored
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn is_link(&self, src: &Path, dst: &Path) -> bool {
+        self.inner.is_link(src, dst)
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(prefix)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn index(&self, path: &Path, id: &str) -> io::Result<()> {
+        let id_path = id_path(id);
+        if let Some(parent) = id_path.parent() {
+            self.inner.create_dir(parent)?;
+        }
+        match self.inner.link(path, &id_path) {
+            // Indexed by an earlier run.
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            linked => linked,
+        }
+    }
+
+    fn clean_up(&self) -> io::Result<()> {
+        self.inner.clean_up()?;
+        for staged in self.staged.lock().unwrap().drain() {
+            match self.inner.remove(&staged) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                removed => removed?,
+            }
+        }
+        Ok(())
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        self.inner.local_root()
+    }
+}
+
+/// Converts an existing archive to content-addressed storage, linking every file to its blob
+/// with `links` and indexing the media by the ids of the `manifest` (or of the sidecars).
+pub fn dedup_archive(catalog: &Catalog, manifest: &Manifest, links: LinkMode) {
+    let storage = ContentAddressedStorage::new(Arc::new(LocalStorage::new(&catalog.root, links)));
+    let mut ids = HashMap::<&Path, Vec<&str>>::new();
+    for (id, entry) in manifest
+        .items
+        .iter()
+        .filter(|(_, entry)| entry.deleted.is_none())
+    {
+        ids.entry(&entry.path).or_default().push(id);
+    }
+    let mut converted = 0;
+    let mut duplicates = 0;
+    let mut saved = 0;
+    for item in catalog.unique_items() {
+        let is_symlink = fs::symlink_metadata(catalog.root.join(&item.path))
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false);
+        // A symbolic link is already a link to a blob.
+        if !is_symlink {
+            match storage.adopt(&item.path) {
+                Ok(None) => (),
+                Ok(Some((duplicate, size))) => {
+                    converted += 1;
+                    if duplicate {
+                        duplicates += 1;
+                        saved += size;
+                    }
+                }
+                Err(e) => {
+                    warn!("Could not deduplicate {:?}: {}", item.path, e);
+                    continue;
+                }
+            }
+        }
+        // Indexed like the media stored by a content-addressed backup, so it isn't downloaded
+        // again for another path.
+        let sidecar_id = item
+            .sidecar
+            .as_ref()
+            .and_then(|sidecar| sidecar.id.as_deref());
+        for id in ids
+            .get(item.path.as_path())
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(sidecar_id)
+            .collect::<HashSet<_>>()
+        {
+            if let Err(e) = storage.index(&item.path, id) {
+                warn!("Could not index {:?} as {}: {}", item.path, id, e);
+            }
+        }
+    }
+    info!(
+        "Moved {} files to the blob store, {} were duplicates ({} bytes saved)",
+        converted, duplicates, saved
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::worker;
+    use google_photoslibrary1::MediaItem;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_index() {
+        let root = std::env::temp_dir().join(format!("gpb-dedup-{}", std::process::id()));
+        let storage =
+            ContentAddressedStorage::new(Arc::new(LocalStorage::new(&root, LinkMode::Symlink)));
+        let put = |path: &str, content: &'static str| {
+            storage
+                .put(Path::new(path), Box::new(Cursor::new(content)), None)
+                .unwrap()
+        };
+        put("a.jpg", "a");
+        put("b.jpg", "a");
+        assert_eq!(storage.list(Path::new(BLOBS_FOLDER)).unwrap().len(), 1);
+
+        storage.index(Path::new("a.jpg"), "id-a").unwrap();
+        storage.index(Path::new("a.jpg"), "id-a").unwrap();
+        storage.create_dir(Path::new("Album")).unwrap();
+        let media = MediaItem {
+            id: Some("id-a".to_string()),
+            ..MediaItem::default()
+        };
+        assert!(worker::link_known(
+            &storage,
+            &media,
+            Path::new("Album/a.jpg")
+        ));
+        assert_eq!(fs::read_to_string(root.join("Album/a.jpg")).unwrap(), "a");
+        let unknown = MediaItem {
+            id: Some("id-c".to_string()),
+            ..MediaItem::default()
+        };
+        assert!(!worker::link_known(
+            &storage,
+            &unknown,
+            Path::new("Album/c.jpg")
+        ));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_archive() {
+        let root = std::env::temp_dir().join(format!("gpb-dedup-archive-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.jpg"), "a").unwrap();
+        fs::write(root.join("b.jpg"), "a").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.record("id-a", Path::new("a.jpg"));
+        manifest.record("id-b", Path::new("b.jpg"));
+
+        for _ in 0..2 {
+            let catalog = Catalog::scan(&root).unwrap();
+            dedup_archive(&catalog, &manifest, LinkMode::Hardlink);
+        }
+        let blob = root.join(blob_path(&hex::encode(Sha256::digest(b"a"))));
+        for path in &["a.jpg", "b.jpg", ".blobs/ids/id-a", ".blobs/ids/id-b"] {
+            let path = root.join(path);
+            assert!(!fs::symlink_metadata(&path)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert!(filesystem::same_file(&path, &blob).unwrap());
+        }
+        assert_eq!(fs::read_dir(blob.parent().unwrap()).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_keyed_blob_names() {
+        let root = std::env::temp_dir().join(format!("gpb-dedup-keyed-{}", std::process::id()));
+        let inner: Arc<dyn Storage> = Arc::new(LocalStorage::new(&root, LinkMode::Symlink));
+        let blobs = |key: Option<&[u8]>| {
+            let storage = ContentAddressedStorage::with_key(inner.clone(), key.map(Vec::from));
+            for path in &["a.jpg", "b.jpg"] {
+                storage
+                    .put(Path::new(path), Box::new(Cursor::new("a")), None)
+                    .unwrap();
+            }
+            let mut blobs = storage.list(Path::new(BLOBS_FOLDER)).unwrap();
+            blobs.sort();
+            blobs
+        };
+        let plain = blob_path(&hex::encode(Sha256::digest(b"a")));
+        assert_eq!(blobs(None), vec![plain.clone()]);
+        let keyed = blobs(Some(b"key"));
+        assert_eq!(keyed.len(), 2);
+        assert!(keyed.contains(&plain));
+        // Named the same by every run with the key, so duplicates are still found.
+        assert_eq!(blobs(Some(b"key")), keyed);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::storage::{self, LocalStorage, Storage};
+
+/// A change a run would make to the archive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    CreateDir(PathBuf),
+    Link(PathBuf, PathBuf),
+    Write(PathBuf),
+    Move(PathBuf, PathBuf),
+    Remove(PathBuf),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::CreateDir(path) => write!(f, "Would create folder: {}", path.display()),
+            Change::Link(src, dst) => {
+                write!(f, "Would link: {} -> {}", dst.display(), src.display())
+            }
+            Change::Write(path) => write!(f, "Would write: {}", path.display()),
+            Change::Move(from, to) => {
+                write!(f, "Would move: {} to {}", from.display(), to.display())
+            }
+            Change::Remove(path) => write!(f, "Would remove: {}", path.display()),
+        }
+    }
+}
+
+/// Storage that reads from the archive but only records what would be written to it.
+pub struct DryRunStorage {
+    inner: Arc<dyn Storage>,
+    changes: Mutex<Vec<Change>>,
+    /// Paths that would exist after the changes.
+    created: Mutex<HashSet<PathBuf>>,
+}
+
+impl DryRunStorage {
+    pub fn new(inner: Arc<dyn Storage>) -> Self {
+        Self {
+            inner,
+            changes: Mutex::new(Vec::new()),
+            created: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The storage of the archive of `config`, without creating a local archive that
+    /// doesn't exist yet (an empty folder is what such an archive would be read as).
+    pub fn open(config: &Config) -> io::Result<Self> {
+        let options = config.options();
+        let archive = config.archive();
+        if options.s3.is_none() && !archive.exists() {
+            Ok(Self::new(Arc::new(LocalStorage::new(
+                &archive,
+                options.album_links,
+            ))))
+        } else {
+            Ok(Self::new(storage::open_read_only(config)?))
+        }
+    }
+
+    /// Every change recorded, in the order they would be made.
+    pub fn changes(&self) -> Vec<Change> {
+        self.changes.lock().unwrap().clone()
+    }
+
+    fn record(&self, change: Change, created: Option<&Path>) {
+        if let Some(path) = created {
+            self.created.lock().unwrap().insert(path.to_path_buf());
+        }
+        self.changes.lock().unwrap().push(change);
+    }
+}
+
+impl Storage for DryRunStorage {
+    fn put(
+        &self,
+        path: &Path,
+        _reader: Box<dyn Read + Send>,
+        size: Option<u64>,
+    ) -> io::Result<u64> {
+        self.record(Change::Write(path.to_path_buf()), Some(path));
+        Ok(size.unwrap_or(0))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.created.lock().unwrap().contains(path) || self.inner.exists(path)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        if self.exists(dst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} already exists", dst),
+            ));
+        }
+        self.record(
+            Change::Link(src.to_path_buf(), dst.to_path_buf()),
+            Some(dst),
+        );
+        Ok(())
+    }
+
+    fn is_link(&self, src: &Path, dst: &Path) -> bool {
+        self.inner.is_link(src, dst)
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        match self.inner.list(prefix) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            listed => listed,
+        }
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.record(Change::Remove(path.to_path_buf()), None);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.record(Change::Move(from.to_path_buf(), to.to_path_buf()), Some(to));
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        if !self.exists(path) {
+            self.record(Change::CreateDir(path.to_path_buf()), Some(path));
+        }
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.record(Change::Remove(path.to_path_buf()), None);
+        Ok(())
+    }
+
+    // Thumbnails and other features reading the media back have nothing to read.
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::LinkMode;
+
+    #[test]
+    fn test_records_without_writing() {
+        let root = std::env::temp_dir().join(format!("gpb-dry-run-{}", std::process::id()));
+        let storage = DryRunStorage::new(Arc::new(LocalStorage::new(&root, LinkMode::Symlink)));
+        storage.create_dir(Path::new("Album")).unwrap();
+        storage.create_dir(Path::new("Album")).unwrap();
+        storage
+            .link(Path::new("a.jpg"), Path::new("Album/a.jpg"))
+            .unwrap();
+        assert!(storage
+            .link(Path::new("a.jpg"), Path::new("Album/a.jpg"))
+            .is_err());
+        assert!(storage.list(Path::new("Album")).unwrap().is_empty());
+        assert_eq!(
+            storage.changes(),
+            vec![
+                Change::CreateDir(PathBuf::from("Album")),
+                Change::Link(PathBuf::from("a.jpg"), PathBuf::from("Album/a.jpg")),
+            ]
+        );
+        assert!(!root.exists());
+    }
+}
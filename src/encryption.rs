@@ -0,0 +1,522 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::{KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key};
+use hmac::Hmac;
+use log::{debug, info, warn};
+use sha2::Sha256;
+
+use crate::filesystem::{self, LinkMode};
+use crate::storage::Storage;
+use crate::volumes::{self, IndexEntry};
+
+/// Environment variable with the passphrase, when no key file is given.
+pub const PASSPHRASE_VAR: &str = "GOOGLE_PHOTOS_BACKUP_PASSPHRASE";
+
+/// Encrypted files start with this, followed by the salt the key was derived with and the
+/// nonce prefix of the STREAM (AES-256-GCM over chunks of `CHUNK_SIZE` bytes).
+const MAGIC: &[u8; 8] = b"GPBENC01";
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 7;
+const HEADER_SIZE: usize = MAGIC.len() + SALT_SIZE + NONCE_SIZE;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+const PBKDF2_ROUNDS: usize = 100_000;
+/// Salt of the key of the blob names, which has to be the same for every run.
+const BLOB_KEY_SALT: &[u8] = b"google-photos-backup blob names";
+
+type Salt = [u8; SALT_SIZE];
+
+/// Reads the passphrase from `key_file`, or from the `GOOGLE_PHOTOS_BACKUP_PASSPHRASE`
+/// environment variable without one.
+pub fn passphrase(key_file: Option<&Path>) -> io::Result<Vec<u8>> {
+    read_passphrase(key_file, PASSPHRASE_VAR)
+}
+
+/// Reads the passphrase from `key_file`, or from the environment variable `var` without one.
+pub fn read_passphrase(key_file: Option<&Path>, var: &str) -> io::Result<Vec<u8>> {
+    let passphrase = match key_file {
+        Some(path) => {
+            let mut contents = fs::read(path)?;
+            while contents.last() == Some(&b'\n') || contents.last() == Some(&b'\r') {
+                contents.pop();
+            }
+            contents
+        }
+        None => std::env::var(var)
+            .map_err(|_| io::Error::other(format!("no key file given and {} is not set", var)))?
+            .into_bytes(),
+    };
+    if passphrase.is_empty() {
+        return Err(io::Error::other("the passphrase is empty"));
+    }
+    Ok(passphrase)
+}
+
+fn derive_key(passphrase: &[u8], salt: &Salt) -> Key<Aes256Gcm> {
+    let mut key = Key::<Aes256Gcm>::default();
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+/// Key of the names of the blobs of an encrypted content-addressed backup, so the names (hashes
+/// of the plaintext) don't tell whether the backup has a given file.
+pub fn blob_key(passphrase: &[u8]) -> Vec<u8> {
+    let mut key = vec![0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, BLOB_KEY_SALT, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+/// Whether `contents` were encrypted by this module.
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Size of `size` bytes of plaintext once encrypted. There is always a last chunk, possibly
+/// empty, so a full chunk is never the last one.
+pub fn encrypted_size(size: u64) -> u64 {
+    let chunks = size / CHUNK_SIZE as u64 + 1;
+    HEADER_SIZE as u64 + size + chunks * TAG_SIZE as u64
+}
+
+/// Reads as much as possible into `buffer`, returning less than its length only at the end.
+fn read_full(reader: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Encrypts everything read from `inner`.
+struct EncryptingReader {
+    inner: Box<dyn Read + Send>,
+    encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+    output: Vec<u8>,
+    position: usize,
+    plaintext: Arc<AtomicU64>,
+}
+
+impl EncryptingReader {
+    fn new(inner: Box<dyn Read + Send>, key: &Key<Aes256Gcm>, salt: &Salt) -> Self {
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut output = Vec::with_capacity(HEADER_SIZE);
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(salt);
+        output.extend_from_slice(&nonce);
+        Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(
+                Aes256Gcm::new(key),
+                nonce.as_ref().into(),
+            )),
+            output,
+            position: 0,
+            plaintext: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let read = read_full(&mut self.inner, &mut chunk)?;
+        self.plaintext.fetch_add(read as u64, Ordering::SeqCst);
+        let encrypted = if read == CHUNK_SIZE {
+            self.encryptor
+                .as_mut()
+                .unwrap()
+                .encrypt_next(&chunk[..read])
+        } else {
+            self.encryptor.take().unwrap().encrypt_last(&chunk[..read])
+        };
+        self.output = encrypted.map_err(|_| io::Error::other("could not encrypt"))?;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl Read for EncryptingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.output.len() {
+            if self.encryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = std::cmp::min(buf.len(), self.output.len() - self.position);
+        buf[..len].copy_from_slice(&self.output[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// Decrypts a file written by the encrypting storage from `reader` into `writer`, deriving the
+/// key from the passphrase (keys are cached by salt since deriving them is slow on purpose).
+/// Returns the number of plaintext bytes, or `None` if the file wasn't encrypted.
+pub fn decrypt(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    passphrase: &[u8],
+    keys: &mut HashMap<Salt, Key<Aes256Gcm>>,
+) -> io::Result<Option<u64>> {
+    let mut header = [0; HEADER_SIZE];
+    if read_full(reader, &mut header)? < HEADER_SIZE || &header[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    let mut salt = [0; SALT_SIZE];
+    salt.copy_from_slice(&header[MAGIC.len()..MAGIC.len() + SALT_SIZE]);
+    let key = keys
+        .entry(salt)
+        .or_insert_with(|| derive_key(passphrase, &salt));
+    let mut decryptor = DecryptorBE32::from_aead(
+        Aes256Gcm::new(key),
+        header[MAGIC.len() + SALT_SIZE..].into(),
+    );
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "wrong passphrase or corrupt file",
+        )
+    };
+
+    let mut chunk = vec![0; CHUNK_SIZE + TAG_SIZE];
+    let mut written = 0;
+    loop {
+        let read = read_full(reader, &mut chunk)?;
+        if read == chunk.len() {
+            let plaintext = decryptor.decrypt_next(&chunk[..]).map_err(|_| invalid())?;
+            writer.write_all(&plaintext)?;
+            written += plaintext.len() as u64;
+        } else {
+            let plaintext = decryptor
+                .decrypt_last(&chunk[..read])
+                .map_err(|_| invalid())?;
+            writer.write_all(&plaintext)?;
+            written += plaintext.len() as u64;
+            return Ok(Some(written));
+        }
+    }
+}
+
+/// Encrypts `plaintext` in memory, in the same format as the files of encrypted backups.
+pub fn encrypt_bytes(plaintext: &[u8], passphrase: &[u8]) -> io::Result<Vec<u8>> {
+    let mut salt = [0; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let mut reader = EncryptingReader::new(
+        Box::new(io::Cursor::new(plaintext.to_vec())),
+        &derive_key(passphrase, &salt),
+        &salt,
+    );
+    let mut encrypted = Vec::new();
+    reader.read_to_end(&mut encrypted)?;
+    Ok(encrypted)
+}
+
+/// Decrypts what `encrypt_bytes` encrypted.
+pub fn decrypt_bytes(mut encrypted: &[u8], passphrase: &[u8]) -> io::Result<Vec<u8>> {
+    let mut plaintext = Vec::new();
+    match decrypt(
+        &mut encrypted,
+        &mut plaintext,
+        passphrase,
+        &mut HashMap::new(),
+    )? {
+        Some(_) => Ok(plaintext),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "not encrypted")),
+    }
+}
+
+/// Storage encrypting everything written to it with a key derived from a passphrase.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    key: Key<Aes256Gcm>,
+    salt: Salt,
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, passphrase: &[u8]) -> Self {
+        // One salt (and so one key derivation) per run, stored in the header of every file.
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            inner,
+            key: derive_key(passphrase, &salt),
+            salt,
+        }
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn put(&self, path: &Path, reader: Box<dyn Read + Send>, size: Option<u64>) -> io::Result<u64> {
+        let reader = EncryptingReader::new(reader, &self.key, &self.salt);
+        let plaintext = reader.plaintext.clone();
+        self.inner
+            .put(path, Box::new(reader), size.map(encrypted_size))?;
+        // Report the plaintext size so it can be checked against the size of the download.
+        Ok(plaintext.load(Ordering::SeqCst))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        self.inner.link(src, dst)
+    }
+
+    fn is_link(&self, src: &Path, dst: &Path) -> bool {
+        self.inner.is_link(src, dst)
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(prefix)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn index(&self, path: &Path, id: &str) -> io::Result<()> {
+        self.inner.index(path, id)
+    }
+
+    fn clean_up(&self) -> io::Result<()> {
+        self.inner.clean_up()
+    }
+
+    // The files can't be read back without the key, so no local root (e.g. for thumbnails).
+}
+
+/// Writes the plaintext of what `open` reads to `destination`, or a copy of it if it isn't
+/// encrypted. Returns whether it was decrypted.
+fn restore_file<R, F>(
+    open: F,
+    destination: &Path,
+    relative: &Path,
+    passphrase: &[u8],
+    keys: &mut HashMap<Salt, Key<Aes256Gcm>>,
+) -> io::Result<bool>
+where
+    R: Read,
+    F: Fn() -> io::Result<R>,
+{
+    let mut writer = io::BufWriter::new(File::create(destination)?);
+    let result = decrypt(&mut open()?, &mut writer, passphrase, keys)
+        .and_then(|result| writer.flush().map(|_| result));
+    match result.map_err(|e| {
+        let _ = fs::remove_file(destination);
+        io::Error::new(e.kind(), format!("{:?}: {}", relative, e))
+    })? {
+        Some(_) => Ok(true),
+        None => {
+            // Written before encryption was enabled.
+            debug!("{:?} is not encrypted, copying it", relative);
+            drop(writer);
+            io::copy(&mut open()?, &mut File::create(destination)?)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Extracts the entries of the tar volumes in `root` to `output`, decrypting the files and
+/// recreating the album links.
+fn restore_volumes(
+    root: &Path,
+    output: &Path,
+    passphrase: &[u8],
+    keys: &mut HashMap<Salt, Key<Aes256Gcm>>,
+) -> io::Result<(usize, usize)> {
+    let (mut decrypted, mut copied) = (0, 0);
+    let mut entries = volumes::read_index(root)?
+        .into_values()
+        .collect::<Vec<IndexEntry>>();
+    // Media first, so album links are made to restored files.
+    entries.sort_by(|a, b| (a.link.is_some(), &a.path).cmp(&(b.link.is_some(), &b.path)));
+    for entry in entries {
+        let destination = output.join(&entry.path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Some(link) = &entry.link {
+            let _ = fs::remove_file(&destination);
+            filesystem::link(&output.join(link), &destination, LinkMode::default())?;
+            continue;
+        }
+        let volume = root.join(&entry.volume);
+        let open = || {
+            let mut file = File::open(&volume)?;
+            file.seek(io::SeekFrom::Start(entry.offset))?;
+            Ok(file.take(entry.size))
+        };
+        if restore_file(open, &destination, &entry.path, passphrase, keys)? {
+            decrypted += 1;
+        } else {
+            copied += 1;
+        }
+    }
+    Ok((decrypted, copied))
+}
+
+fn restore_folder(
+    root: &Path,
+    folder: &Path,
+    output: &Path,
+    passphrase: &[u8],
+    keys: &mut HashMap<Salt, Key<Aes256Gcm>>,
+) -> io::Result<(usize, usize)> {
+    let (mut decrypted, mut copied) = (0, 0);
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        let relative = path.strip_prefix(root).unwrap().to_path_buf();
+        let destination = output.join(&relative);
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            // Album links, pointed at the restored media when they point inside the backup.
+            let target = fs::read_link(&path)?;
+            let target = match target.strip_prefix(root) {
+                Ok(inside) => output.join(inside),
+                Err(_) => target,
+            };
+            let _ = fs::remove_file(&destination);
+            filesystem::link(&target, &destination, LinkMode::default())?;
+        } else if metadata.is_dir() {
+            fs::create_dir_all(&destination)?;
+            let (d, c) = restore_folder(root, &path, output, passphrase, keys)?;
+            decrypted += d;
+            copied += c;
+        } else if restore_file(
+            || File::open(&path),
+            &destination,
+            &relative,
+            passphrase,
+            keys,
+        )? {
+            decrypted += 1;
+        } else {
+            copied += 1;
+        }
+    }
+    Ok((decrypted, copied))
+}
+
+/// Writes a plaintext copy of the encrypted backup in `root` to `output`.
+pub fn restore_local(root: &Path, output: &Path, passphrase: &[u8]) -> io::Result<()> {
+    let root = root.canonicalize()?;
+    fs::create_dir_all(output)?;
+    let output = output.canonicalize()?;
+    if output.starts_with(&root) {
+        warn!("Restoring inside the backup folder, the restored files will be backed up too");
+    }
+    let mut keys = HashMap::new();
+    // A backup in tar volumes is restored as a folder tree.
+    let (decrypted, copied) = if root.join(volumes::INDEX_FILE).exists() {
+        restore_volumes(&root, &output, passphrase, &mut keys)?
+    } else {
+        restore_folder(&root, &root, &output, passphrase, &mut keys)?
+    };
+    info!(
+        "Decrypted {} files to {:?} ({} files were not encrypted)",
+        decrypted, output, copied
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let salt = [7; SALT_SIZE];
+        let key = derive_key(b"secret", &salt);
+        for size in &[0, 10, CHUNK_SIZE, 2 * CHUNK_SIZE + 3] {
+            let plaintext = (0..*size).map(|i| i as u8).collect::<Vec<_>>();
+            let mut reader =
+                EncryptingReader::new(Box::new(io::Cursor::new(plaintext.clone())), &key, &salt);
+            let mut encrypted = Vec::new();
+            reader.read_to_end(&mut encrypted).unwrap();
+            assert_eq!(encrypted.len() as u64, encrypted_size(*size as u64));
+
+            let mut keys = HashMap::new();
+            keys.insert(salt, key);
+            let mut decrypted = Vec::new();
+            let written = decrypt(&mut &encrypted[..], &mut decrypted, b"secret", &mut keys);
+            assert_eq!(written.unwrap(), Some(*size as u64));
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let salt = [7; SALT_SIZE];
+        let key = derive_key(b"secret", &salt);
+        let mut reader = EncryptingReader::new(Box::new(io::Cursor::new(vec![1; 10])), &key, &salt);
+        let mut encrypted = Vec::new();
+        reader.read_to_end(&mut encrypted).unwrap();
+        let result = decrypt(
+            &mut &encrypted[..],
+            &mut Vec::new(),
+            b"guess",
+            &mut HashMap::new(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_restore_volumes() {
+        let folder = std::env::temp_dir().join(format!("gpb-restore-{}", std::process::id()));
+        let (root, output) = (folder.join("backup"), folder.join("restored"));
+        {
+            let tar = Arc::new(volumes::TarStorage::new(&root, 1024 * 1024).unwrap());
+            let storage = EncryptedStorage::new(tar.clone(), b"secret");
+            let content = b"photo".to_vec();
+            storage
+                .put(
+                    Path::new("a.jpg"),
+                    Box::new(io::Cursor::new(content)),
+                    Some(5),
+                )
+                .unwrap();
+            tar.put(
+                Path::new("old.jpg"),
+                Box::new(io::Cursor::new(b"old".to_vec())),
+                None,
+            )
+            .unwrap();
+            storage
+                .link(Path::new("a.jpg"), Path::new("Album/a.jpg"))
+                .unwrap();
+        }
+
+        restore_local(&root, &output, b"secret").unwrap();
+        assert_eq!(fs::read(output.join("a.jpg")).unwrap(), b"photo");
+        assert_eq!(fs::read(output.join("old.jpg")).unwrap(), b"old");
+        assert_eq!(fs::read(output.join("Album/a.jpg")).unwrap(), b"photo");
+        assert!(!output.join(volumes::INDEX_FILE).exists());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
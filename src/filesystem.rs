@@ -1,26 +1,147 @@
-use std::path::Path;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+pub fn ensure_folder(path: &Path) {
+    std::fs::create_dir_all(path)
+        .unwrap_or_else(|e| panic!("Could not create folder '{:?}': {}", path, e));
+}
+
+/// How album folders point to the media in the library.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    /// Symbolic link with the absolute path of the media.
+    Symlink,
+    /// Symbolic link with the path of the media relative to the album folder, which keeps
+    /// working when the backup folder is moved.
+    Relative,
+    Hardlink,
+    Copy,
+}
+
+impl Default for LinkMode {
+    fn default() -> Self {
+        if cfg!(target_os = "windows") {
+            LinkMode::Hardlink
+        } else {
+            LinkMode::Symlink
+        }
+    }
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "symlink" => Ok(LinkMode::Symlink),
+            "relative" => Ok(LinkMode::Relative),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "copy" => Ok(LinkMode::Copy),
+            _ => Err(format!("unknown album link mode: {}", s)),
+        }
+    }
+}
 
 #[cfg(not(target_os = "windows"))]
-use std::os::unix::fs::symlink;
+fn soft_link(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+}
 
-// In Windows I opted to go for a hard link. The reason being that software in Windows
-// very often (if not always) doesn't process soft links as if it were a file: a good
-// example is the Windows 10 Photos app, which seems to ignore soft links completely.
 #[cfg(target_os = "windows")]
-use std::fs::hard_link as symlink;
+fn soft_link(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(src, dst)
+}
 
-use log::debug;
+/// Makes the file at `src` available at `dst` too, both absolute paths.
+pub fn link(src: &Path, dst: &Path, mode: LinkMode) -> io::Result<()> {
+    match mode {
+        LinkMode::Symlink => soft_link(src, dst),
+        LinkMode::Relative => soft_link(&relative_path(src, dst), dst),
+        LinkMode::Hardlink => fs::hard_link(src, dst),
+        LinkMode::Copy => {
+            if dst.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} already exists", dst),
+                ));
+            }
+            fs::copy(src, dst).map(|_| ())
+        }
+    }
+}
 
-pub fn ensure_folder(path: &Path) {
-    std::fs::create_dir_all(path)
-        .unwrap_or_else(|e| panic!("Could not create folder '{:?}': {}", path, e));
+/// Path to `src` relative to the folder of `dst`.
+pub fn relative_path(src: &Path, dst: &Path) -> PathBuf {
+    let folder = dst
+        .parent()
+        .map(|parent| parent.components().collect::<Vec<_>>())
+        .unwrap_or_default();
+    let target = src.components().collect::<Vec<_>>();
+    let common = folder
+        .iter()
+        .zip(target.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut path = PathBuf::new();
+    for component in &folder[common..] {
+        if let Component::Normal(_) = component {
+            path.push("..");
+        }
+    }
+    for component in &target[common..] {
+        path.push(component.as_os_str());
+    }
+    path
 }
 
-pub fn create_symlink(src: &Path, dst: &Path) {
-    match symlink(src, dst) {
-        Err(e) => debug!("Could not point {:?} to {:?}: {}", dst, src, e),
-        Ok(()) => (),
-    };
+/// Resolves `.` and `..` in `path` without looking at the filesystem.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            _ => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// Whether the two paths are the same file, e.g. hard links of one another.
+#[cfg(not(target_os = "windows"))]
+pub fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(target_os = "windows")]
+pub fn same_file(_a: &Path, _b: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Whether the two files have the same content, e.g. a hard link or a copy of one another.
+pub fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (fs::File::open(a)?, fs::File::open(b)?);
+    let (mut buffer_a, mut buffer_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let read = a.read(&mut buffer_a)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buffer_b[..read])?;
+        if buffer_a[..read] != buffer_b[..read] {
+            return Ok(false);
+        }
+    }
 }
 
 pub trait FilesystemSafeEscaper {
@@ -41,7 +162,40 @@ impl FilesystemSafeEscaper for String {
 
 #[cfg(test)]
 mod tests {
-    use super::FilesystemSafeEscaper;
+    use super::{normalize, relative_path, FilesystemSafeEscaper};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(Path::new("IMG_1.JPG"), Path::new("Album/IMG_1.JPG")),
+            PathBuf::from("../IMG_1.JPG")
+        );
+        assert_eq!(
+            relative_path(Path::new("IMG_1.JPG"), Path::new("Album/Alice/IMG_1.JPG")),
+            PathBuf::from("../../IMG_1.JPG")
+        );
+        assert_eq!(
+            relative_path(Path::new("a/IMG_1.JPG"), Path::new("IMG_1.JPG")),
+            PathBuf::from("a/IMG_1.JPG")
+        );
+        assert_eq!(
+            relative_path(
+                Path::new("/backup/IMG_1.JPG"),
+                Path::new("/backup/Album/0001_IMG_1.JPG")
+            ),
+            PathBuf::from("../IMG_1.JPG")
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("/backup/Album/../IMG_1.JPG")),
+            PathBuf::from("/backup/IMG_1.JPG")
+        );
+    }
+
     #[test]
     #[cfg(not(target_os = "windows"))]
     fn test_escape() {
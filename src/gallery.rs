@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use log::info;
+
+use crate::catalog::{Catalog, Item};
+use crate::filesystem::ensure_folder;
+use crate::thumbnails;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 0 2em 2em 2em; background: #fafafa; color: #222; }
+nav { padding: 1em 0; border-bottom: 1px solid #ddd; margin-bottom: 1em; }
+nav a { margin-right: 1.5em; }
+.grid { display: flex; flex-wrap: wrap; gap: 8px; }
+.tile { width: 200px; height: 200px; display: flex; align-items: center; justify-content: center;
+        overflow: hidden; background: #eee; text-align: center; word-break: break-all; }
+.tile img { width: 100%; height: 100%; object-fit: cover; }
+.album { width: 200px; }
+.detail img, .detail video { max-width: 100%; max-height: 80vh; }
+table { border-collapse: collapse; margin-top: 1em; }
+td { padding: 0.2em 1em 0.2em 0; vertical-align: top; }
+";
+
+/// Static HTML pages to browse an archive offline: an album index, the main library, a
+/// timeline grouped by month and a detail page for each media item.
+pub struct Gallery<'a> {
+    catalog: &'a Catalog,
+    output: PathBuf,
+    archive_url: String,
+    pages: HashMap<PathBuf, usize>,
+}
+
+impl<'a> Gallery<'a> {
+    pub fn new(catalog: &'a Catalog, output: &Path) -> io::Result<Self> {
+        ensure_folder(&output.join("items"));
+        let output = output.canonicalize()?;
+        let archive_url = relative_url(&output, &catalog.root.canonicalize()?);
+
+        let mut pages = HashMap::new();
+        for item in catalog
+            .library
+            .iter()
+            .chain(catalog.albums.iter().flat_map(|album| album.items.iter()))
+        {
+            let next = pages.len();
+            pages.entry(item.path.clone()).or_insert(next);
+        }
+
+        Ok(Self {
+            catalog,
+            output,
+            archive_url,
+            pages,
+        })
+    }
+
+    pub fn generate(&self) -> io::Result<()> {
+        info!("Generating gallery in {:?}", self.output);
+        self.write_index()?;
+        self.write_grid(
+            "library.html",
+            "Library",
+            &self.catalog.library.iter().collect::<Vec<_>>(),
+        )?;
+        for (index, album) in self.catalog.albums.iter().enumerate() {
+            self.write_grid(
+                &album_page(index),
+                &album.name,
+                &album.items.iter().collect::<Vec<_>>(),
+            )?;
+        }
+        self.write_timeline()?;
+        for item in self.catalog.library.iter().chain(
+            self.catalog
+                .albums
+                .iter()
+                .flat_map(|album| album.items.iter()),
+        ) {
+            self.write_item(item)?;
+        }
+        info!(
+            "Generated gallery with {} albums and {} items",
+            self.catalog.albums.len(),
+            self.pages.len()
+        );
+        Ok(())
+    }
+
+    fn write_page(&self, name: &str, title: &str, prefix: &str, body: &str) -> io::Result<()> {
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+             <style>{style}</style>\n</head>\n<body>\n<nav><a href=\"{prefix}index.html\">Albums</a>\
+             <a href=\"{prefix}library.html\">Library</a><a href=\"{prefix}timeline.html\">Timeline</a>\
+             </nav>\n<h1>{title}</h1>\n{body}</body>\n</html>\n",
+            title = escape_html(title),
+            style = STYLE,
+            prefix = prefix,
+            body = body
+        );
+        fs::write(self.output.join(name), html)
+    }
+
+    fn write_index(&self) -> io::Result<()> {
+        let mut body = String::from("<div class=\"grid\">\n");
+        for (index, album) in self.catalog.albums.iter().enumerate() {
+            let cover = album
+                .items
+                .first()
+                .map(|item| self.preview(item))
+                .unwrap_or_default();
+            let _ = writeln!(
+                body,
+                "<div class=\"album\"><a href=\"{}\"><div class=\"tile\">{}</div>{}</a> ({})</div>",
+                album_page(index),
+                cover,
+                escape_html(&album.name),
+                album.items.len()
+            );
+        }
+        body.push_str("</div>\n");
+        self.write_page("index.html", "Albums", "", &body)
+    }
+
+    fn write_grid(&self, name: &str, title: &str, items: &[&Item]) -> io::Result<()> {
+        let body = self.grid(items);
+        self.write_page(name, title, "", &body)
+    }
+
+    fn write_timeline(&self) -> io::Result<()> {
+        // Newest first, with the media we don't know the date of at the end.
+        let mut months: BTreeMap<Option<String>, Vec<&Item>> = BTreeMap::new();
+        for item in self.catalog.unique_items() {
+            let month = item
+                .creation_time()
+                .filter(|time| time.len() >= 7)
+                .map(|time| time[..7].to_string());
+            months.entry(month).or_default().push(item);
+        }
+
+        let mut body = String::new();
+        for (month, items) in months.iter().rev() {
+            let heading = month.as_ref().map_or("Unknown date", String::as_str);
+            let _ = writeln!(body, "<h2>{}</h2>", escape_html(heading));
+            body.push_str(&self.grid(items));
+        }
+        self.write_page("timeline.html", "Timeline", "", &body)
+    }
+
+    fn write_item(&self, item: &Item) -> io::Result<()> {
+        let prefix = "../";
+        let src = format!("{}{}{}", prefix, self.archive_url, encode_path(&item.path));
+        let mut body = String::from("<div class=\"detail\">\n");
+        if item.is_image() {
+            let _ = writeln!(
+                body,
+                "<a href=\"{}\"><img src=\"{}{}\"></a>",
+                src,
+                prefix,
+                self.image_url(item, thumbnails::SIZES[1])
+            );
+        } else if item.is_video() {
+            let _ = writeln!(body, "<video controls src=\"{}\"></video>", src);
+        } else {
+            let _ = writeln!(
+                body,
+                "<a href=\"{}\">{}</a>",
+                src,
+                escape_html(&item.name())
+            );
+        }
+        body.push_str("</div>\n<table>\n");
+
+        let mut rows = vec![("Path", item.path.to_string_lossy().to_string())];
+        if let Some(sidecar) = &item.sidecar {
+            let dimensions = match (&sidecar.width, &sidecar.height) {
+                (Some(width), Some(height)) => Some(format!("{} x {}", width, height)),
+                _ => None,
+            };
+            let camera = match (&sidecar.camera_make, &sidecar.camera_model) {
+                (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+                (make, model) => make.clone().or_else(|| model.clone()),
+            };
+            let contributor = sidecar
+                .contributor
+                .as_ref()
+                .and_then(|contributor| contributor.display_name.clone());
+            let fields = [
+                ("Filename", &sidecar.filename),
+                ("Description", &sidecar.description),
+                ("Created", &sidecar.creation_time),
+                ("Type", &sidecar.mime_type),
+                ("Dimensions", &dimensions),
+                ("Camera", &camera),
+                ("Contributor", &contributor),
+                ("Id", &sidecar.id),
+            ];
+            for (name, value) in fields.iter() {
+                if let Some(value) = value {
+                    rows.push((name, value.clone()));
+                }
+            }
+        }
+        for (name, value) in rows {
+            let _ = writeln!(
+                body,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                name,
+                escape_html(&value)
+            );
+        }
+        body.push_str("</table>\n");
+        if let Some(url) = item.sidecar.as_ref().and_then(|s| s.product_url.as_ref()) {
+            let _ = writeln!(
+                body,
+                "<p><a href=\"{}\">View in Google Photos</a></p>",
+                escape_html(url)
+            );
+        }
+
+        self.write_page(
+            &format!("items/{}.html", self.pages[&item.path]),
+            &item.name(),
+            prefix,
+            &body,
+        )
+    }
+
+    fn grid(&self, items: &[&Item]) -> String {
+        let mut html = String::from("<div class=\"grid\">\n");
+        for item in items {
+            let _ = writeln!(
+                html,
+                "<a class=\"tile\" href=\"items/{}.html\" title=\"{}\">{}</a>",
+                self.pages[&item.path],
+                escape_html(&item.name()),
+                self.preview(item)
+            );
+        }
+        html.push_str("</div>\n");
+        html
+    }
+
+    fn preview(&self, item: &Item) -> String {
+        if item.is_image() {
+            format!(
+                "<img loading=\"lazy\" src=\"{}\">",
+                self.image_url(item, thumbnails::SIZES[0])
+            )
+        } else {
+            escape_html(&item.name())
+        }
+    }
+
+    /// URL of the thumbnail of `size` when it has been generated, of the original otherwise.
+    fn image_url(&self, item: &Item, size: u32) -> String {
+        let thumbnail = thumbnails::path(&self.catalog.root, size, &item.target);
+        let path = match thumbnail.strip_prefix(&self.catalog.root) {
+            Ok(relative) if thumbnail.exists() => relative.to_path_buf(),
+            _ => item.path.clone(),
+        };
+        format!("{}{}", self.archive_url, encode_path(&path))
+    }
+}
+
+fn album_page(index: usize) -> String {
+    format!("album-{}.html", index)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn encode_component(component: &str) -> String {
+    component
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn encode_path(path: &Path) -> String {
+    path.components()
+        .map(|component| encode_component(&component.as_os_str().to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// URL (ending in `/` unless empty) that leads from the `from` folder to the `to` folder.
+fn relative_url(from: &Path, to: &Path) -> String {
+    let from = from.components().collect::<Vec<Component>>();
+    let to = to.components().collect::<Vec<Component>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut url = "../".repeat(from.len() - common);
+    for component in &to[common..] {
+        url.push_str(&encode_component(&component.as_os_str().to_string_lossy()));
+        url.push('/');
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_path, escape_html, relative_url};
+    use std::path::Path;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html("<b>Tom & \"Jerry\"</b>"),
+            "&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(
+            encode_path(Path::new("Summer 2018/IMG#1.jpg")),
+            "Summer%202018/IMG%231.jpg"
+        );
+    }
+
+    #[test]
+    #[cfg(not(target_os = "windows"))]
+    fn test_relative_url() {
+        assert_eq!(
+            relative_url(Path::new("/a/b/.gallery"), Path::new("/a/b")),
+            "../"
+        );
+        assert_eq!(
+            relative_url(Path::new("/tmp/out"), Path::new("/a/b c")),
+            "../../a/b%20c/"
+        );
+        assert_eq!(relative_url(Path::new("/a"), Path::new("/a")), "");
+    }
+}
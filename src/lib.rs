@@ -1,9 +1,33 @@
 pub mod album;
 pub mod auth;
+pub mod catalog;
 pub mod cli;
 pub mod config;
+pub mod config_file;
+pub mod daemon;
+pub mod dedup;
+pub mod dry_run;
+pub mod encryption;
 pub mod filesystem;
 mod filtering;
+pub mod gallery;
 pub mod library;
+pub mod list;
+pub mod lock;
+pub mod manifest;
 mod media_item;
+pub mod mirror;
+pub mod profile;
+pub mod queue;
+pub mod relink;
+pub mod report;
+pub mod s3;
+pub mod shutdown;
+pub mod sidecar;
+pub mod status;
+pub mod storage;
+pub mod thumbnails;
+pub mod token_storage;
+pub mod verify;
+pub mod volumes;
 pub mod worker;
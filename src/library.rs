@@ -1,55 +1,123 @@
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use crossbeam_channel::Sender;
 use google_photoslibrary1::{
-    Album, MediaItem, PhotosLibrary, Result, SearchMediaItemsRequest, SearchMediaItemsResponse,
+    Album, Error, MediaItem, PhotosLibrary, Result, SearchMediaItemsRequest,
+    SearchMediaItemsResponse,
 };
 use hyper::{client::Response, net::HttpsConnector, status::StatusCode, Client};
 use hyper_rustls::TlsClient;
-use log::{error, info};
+use log::{error, info, warn};
+use regex::Regex;
 
 use crate::album::AlbumFetcher;
 use crate::auth::{authenticate, LibraryAuthenticator};
 use crate::config::Config;
+use crate::dry_run::{Change, DryRunStorage};
+use crate::filesystem::FilesystemSafeEscaper;
 use crate::filtering::Matcher;
+use crate::manifest::{self, Manifest};
+use crate::mirror::{self, Listing, Plan};
+use crate::queue::{ListingProgress, Pending, WorkQueue};
+use crate::report::{AlbumReport, Report};
+use crate::shutdown;
+use crate::storage::{self, Storage};
 
 const ALBUM_LIST_MAX_PAGE_SIZE: i32 = 50;
 const MEDIA_LIST_MAX_PAGE_SIZE: i32 = 100;
+const MEDIA_BATCH_GET_MAX_IDS: usize = 50;
+
+pub type Hub = PhotosLibrary<Client, LibraryAuthenticator>;
+
+/// Google Photos, with the account of `config`.
+pub fn connect(config: &Config) -> io::Result<Hub> {
+    let auth = authenticate(config)?;
+    let client = Client::with_connector(HttpsConnector::new(TlsClient::new()));
+    Ok(PhotosLibrary::new(client, auth))
+}
 
 pub struct Library {
-    hub: PhotosLibrary<Client, LibraryAuthenticator>,
+    /// Shared with the library of the next run of the daemon.
+    hub: Rc<Hub>,
     config: Config,
+    storage: Arc<dyn Storage>,
+    /// The storage recording the changes, in a dry run.
+    dry_run: Option<Arc<DryRunStorage>>,
+    manifest: Mutex<Manifest>,
+    listing: Mutex<Listing>,
+    report: Arc<Report>,
 }
 
 impl Library {
-    pub fn new(config: &Config) -> Self {
-        let auth = authenticate(config);
-        let client = Client::with_connector(HttpsConnector::new(TlsClient::new()));
-        let hub = PhotosLibrary::new(client, auth);
-
-        // This triggers authentication if there isn't a token present.  (This is an
-        // optimisation for user flow that only affects performance once! Doing this adds one
-        // unnecessary http call but on the other hand it makes it possible for the user to
-        // only deal with permissions once, at the beginning of the program).
-        let req = google_photoslibrary1::SearchMediaItemsRequest {
-            album_id: None,
-            page_size: Some(1),
-            page_token: None,
-            filters: None,
+    pub fn new(config: &Config) -> io::Result<Self> {
+        Self::with_hub(Rc::new(connect(config)?), config)
+    }
+
+    /// The library for a new run with `config`, keeping the connection to Google Photos (and
+    /// its token) unless the account changed.
+    pub fn reconfigure(&self, config: &Config) -> io::Result<Self> {
+        let (old, new) = (self.config.options(), config.options());
+        let same_account = self.config.profile() == config.profile()
+            && old.client_secret == new.client_secret
+            && old.token_key_file == new.token_key_file;
+        if same_account {
+            Self::with_hub(self.hub.clone(), config)
+        } else {
+            Self::new(config)
+        }
+    }
+
+    fn with_hub(hub: Rc<Hub>, config: &Config) -> io::Result<Self> {
+        let manifest = Manifest::load(config).unwrap_or_else(|e| {
+            error!("Could not read the known media, starting over: {}", e);
+            Manifest::default()
+        });
+
+        let dry_run = if config.options().dry_run {
+            Some(Arc::new(DryRunStorage::open(config)?))
+        } else {
+            None
+        };
+        let storage = match &dry_run {
+            Some(dry_run) => dry_run.clone() as Arc<dyn Storage>,
+            None => storage::open(config)?,
         };
-        let _ = hub.media_items().search(req).doit();
-        let _ = hub.albums().list().page_size(1).doit();
 
-        Self {
+        Ok(Self {
             hub,
             config: config.clone(),
-        }
+            storage,
+            dry_run,
+            manifest: Mutex::new(manifest),
+            listing: Mutex::new(Listing::default()),
+            report: Arc::new(Report::new(config)),
+        })
     }
 
     pub fn config(&self) -> Config {
         self.config.clone()
     }
 
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        self.storage.clone()
+    }
+
+    pub fn report(&self) -> Arc<Report> {
+        self.report.clone()
+    }
+
+    /// The changes a dry run would have made to the archive so far.
+    pub fn changes(&self) -> Vec<Change> {
+        self.dry_run
+            .as_ref()
+            .map(|dry_run| dry_run.changes())
+            .unwrap_or_default()
+    }
+
     pub fn search_media_items(
         &self,
         search: SearchMediaItemsRequest,
@@ -58,152 +126,411 @@ impl Library {
     }
 
     fn get_albums(&self) -> Result<Vec<Album>> {
-        info!("Getting albums metadata");
-        let mut page_token = String::from("");
-        let mut albums_found = Vec::new();
-
-        loop {
-            let mut builder = self
-                .hub
-                .albums()
-                .list()
-                .page_size(ALBUM_LIST_MAX_PAGE_SIZE)
-                .exclude_non_app_created_data(false);
-
-            if page_token != "" {
-                builder = builder.page_token(&page_token);
-            }
+        list_albums(&self.hub, self.config.options().album_filter)
+    }
 
-            let result = builder.doit();
-
-            match result {
-                Err(e) => return Err(e),
-                Ok((http_response, albums_response)) => {
-                    if http_response.status == StatusCode::Ok {
-                        if let Some(albums) = albums_response.albums {
-                            albums
-                                .iter()
-                                .filter(|album| album.matches(self.config().options().album_filter))
-                                .for_each(|album| {
-                                    albums_found.push(album.clone());
-                                });
-                        }
-                        if let Some(token) = albums_response.next_page_token {
-                            page_token = token;
-                        } else {
-                            info!("Found {} albums.", albums_found.len());
-                            return Ok(albums_found);
-                        }
-                    }
+    fn get_shared_albums(&self) -> Result<Vec<Album>> {
+        list_shared_albums(&self.hub, self.config.options().album_filter)
+    }
+
+    /// Queues again downloads left by a previous run (or that failed), with fresh download
+    /// URLs.
+    pub fn resume_downloads(
+        &self,
+        pending: &[Pending],
+        queue: &WorkQueue,
+        work_sender: &Sender<(MediaItem, PathBuf)>,
+    ) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let ids = pending
+            .iter()
+            .map(|pending| pending.id.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let found = get_media_items(&self.hub, &ids)?
+            .into_iter()
+            .filter_map(|media| media.id.clone().map(|id| (id, media)))
+            .collect::<HashMap<_, _>>();
+        for pending in pending {
+            match found.get(&pending.id) {
+                Some(media) => work_sender
+                    .send((
+                        media.clone(),
+                        pending.path.parent().unwrap_or(Path::new("")).to_path_buf(),
+                    ))
+                    .unwrap_or_else(|e| error!("Error sending to be processed: {}", e)),
+                None => {
+                    warn!(
+                        "Not downloading {:?} again, {} is no longer in Google Photos",
+                        pending.path, pending.id
+                    );
+                    queue.done(&pending.path);
                 }
-            };
+            }
         }
+        Ok(())
     }
 
-    fn get_shared_albums(&self) -> Result<Vec<Album>> {
-        info!("Getting shared albums metadata");
-        let mut page_token = String::from("");
-        let mut albums_found = Vec::new();
-
-        loop {
-            let mut builder = self
-                .hub
-                .shared_albums()
-                .list()
-                .page_size(ALBUM_LIST_MAX_PAGE_SIZE)
-                .exclude_non_app_created_data(false);
-
-            if page_token != "" {
-                builder = builder.page_token(&page_token);
+    /// Lists the media items and sends them to be downloaded, continuing from `progress` (of
+    /// a previous run) if given. The progress is recorded in `queue` after each page.
+    pub fn download_media_items(
+        &self,
+        work_sender: &Sender<(MediaItem, PathBuf)>,
+        queue: Option<&WorkQueue>,
+        progress: Option<&ListingProgress>,
+    ) -> Result<()> {
+        let mut listed = HashSet::new();
+        let mut page_token = "";
+        match progress {
+            Some(ListingProgress {
+                ids,
+                page_token: Some(token),
+            }) => {
+                info!(
+                    "Retrieving media, after the {} listed by the previous backup...",
+                    ids.len()
+                );
+                listed.extend(ids.iter().cloned());
+                page_token = token;
             }
-
-            let result = builder.doit();
-
-            match result {
-                Err(e) => return Err(e),
-                Ok((http_response, albums_response)) => {
-                    if http_response.status == StatusCode::Ok {
-                        if let Some(albums) = albums_response.shared_albums {
-                            albums
-                                .iter()
-                                .filter(|album| album.matches(self.config().options().album_filter))
-                                .for_each(|album| {
-                                    albums_found.push(album.clone());
-                                });
-                        }
-                        if let Some(token) = albums_response.next_page_token {
-                            page_token = token;
-                        } else {
-                            info!("Found {} shared albums.", albums_found.len());
-                            return Ok(albums_found);
+            _ => info!("Retrieving media..."),
+        }
+        list_media_items_from(&self.hub, page_token, |items, next_page_token| {
+            let ids = items
+                .iter()
+                .filter_map(|media| media.id.clone())
+                .collect::<Vec<_>>();
+            listed.extend(ids.iter().cloned());
+            let mut matching = 0;
+            items
+                .iter()
+                .filter(|media| media.matches(self.config().options().media_filter))
+                .for_each(|media| {
+                    matching += 1;
+                    if let (Some(id), Some(filename)) = (&media.id, &media.filename) {
+                        let path = filename.escape();
+                        self.manifest.lock().unwrap().record(id, Path::new(&path));
+                        // Queued before the page is recorded as listed, as the download
+                        // may not start before the run stops.
+                        if let Some(queue) = queue {
+                            queue.queued(id, Path::new(&path));
                         }
                     }
-                }
-            };
-        }
-    }
-
-    pub fn download_media_items(&self, work_sender: &Sender<(MediaItem, PathBuf)>) -> Result<()> {
-        info!("Retrieving media...");
-        let mut page_token = String::new();
-        let mut count = 0;
-        loop {
-            let mut builder = self
-                .hub
-                .media_items()
-                .list()
-                .page_size(MEDIA_LIST_MAX_PAGE_SIZE);
-            if page_token != "" {
-                builder = builder.page_token(&page_token);
+                    work_sender
+                        .send((media.clone(), PathBuf::new()))
+                        .unwrap_or_else(|e| error!("Error sending to be processed: {}", e));
+                });
+            self.report.listed(items.len(), items.len() - matching);
+            if let Some(queue) = queue {
+                queue.listed_page(&ids, next_page_token);
             }
-            match builder.doit() {
-                Err(e) => return Err(e),
-                Ok((http_response, items_response)) => {
-                    if http_response.status == StatusCode::Ok {
-                        if let Some(items) = items_response.media_items {
-                            count += items.len();
-                            items
-                                .iter()
-                                .filter(|media| media.matches(self.config().options().media_filter))
-                                .for_each(|media| {
-                                    work_sender
-                                        .send((media.clone(), self.config.archive()))
-                                        .unwrap_or_else(|e| {
-                                            error!("Error sending to be processed: {}", e)
-                                        });
-                                });
-                        }
-                        if let Some(token) = items_response.next_page_token {
-                            page_token = token;
-                        } else {
-                            info!("Retrieved {} items", count);
-                            return Ok(());
-                        }
-                    }
-                }
-            };
-        }
+        })
+        .inspect_err(|_| {
+            // Keep what was found so far, for the next run.
+            self.save_manifest()
+        })?;
+        info!("Retrieved {} items", listed.len());
+        // Only a complete listing tells which media was deleted.
+        manifest::handle_deleted(
+            &mut self.manifest.lock().unwrap(),
+            &listed,
+            self.storage.as_ref(),
+            self.config.options().deleted,
+        );
+        self.listing.lock().unwrap().media = Some(listed);
+        self.save_manifest();
+        Ok(())
     }
 
     pub fn download_albums(&self) -> Result<()> {
         let albums = self.get_albums()?;
         for album in albums {
-            let album_path = album.create_dir(&self.config.archive()).unwrap();
-            album
-                .link_media_items(self, &self.config.archive(), &album_path)
-                .unwrap();
+            if shutdown::requested() {
+                self.save_manifest();
+                return Err(Error::Cancelled);
+            }
+            let mut report = album_report(&album, false);
+            let links = album
+                .create_dir(self.storage.as_ref())
+                .map_err(|e| e.to_string())
+                .and_then(|album_path| {
+                    report.path = Some(album_path.clone());
+                    album
+                        .link_media_items(self, &album_path)
+                        .map(|links| (album_path, links))
+                        .map_err(|e| e.to_string())
+                });
+            let mut listing = self.listing.lock().unwrap();
+            match links {
+                Ok((album_path, links)) => {
+                    report.media = links.len();
+                    if let Some(id) = &album.id {
+                        self.manifest
+                            .lock()
+                            .unwrap()
+                            .record_album(id, &album_path, false);
+                    }
+                    listing.links.entry(album_path).or_default().extend(links);
+                }
+                Err(e) => {
+                    error!("Could not back up album {}: {}", album.safe_title(), e);
+                    report.error = Some(e);
+                }
+            }
+            // An album that failed still exists, so mirroring mustn't remove it.
+            if let Some(id) = &album.id {
+                listing.albums.insert(id.clone());
+            }
+            self.report.album(report);
         }
+        self.listing.lock().unwrap().albums_listed = true;
+        self.save_manifest();
         Ok(())
     }
 
     pub fn download_shared_albums(&self, sender: &Sender<(MediaItem, PathBuf)>) -> Result<()> {
         let albums = self.get_shared_albums()?;
         for album in albums {
-            let album_path = album.create_dir(&self.config.archive()).unwrap();
-            album
-                .download_media_items(self, &album_path, sender)
-                .unwrap();
+            if shutdown::requested() {
+                self.save_manifest();
+                return Err(Error::Cancelled);
+            }
+            let mut report = album_report(&album, true);
+            let queued = album
+                .create_dir(self.storage.as_ref())
+                .map_err(|e| e.to_string())
+                .and_then(|album_path| {
+                    report.path = Some(album_path.clone());
+                    album
+                        .download_media_items(self, &album_path, sender)
+                        .map(|queued| (album_path, queued))
+                        .map_err(|e| e.to_string())
+                });
+            let mut listing = self.listing.lock().unwrap();
+            match queued {
+                Ok((album_path, queued)) => {
+                    report.media = queued;
+                    if let Some(id) = &album.id {
+                        self.manifest
+                            .lock()
+                            .unwrap()
+                            .record_album(id, &album_path, true);
+                    }
+                    listing.shared_album_paths.insert(album_path);
+                }
+                Err(e) => {
+                    error!(
+                        "Could not back up shared album {}: {}",
+                        album.safe_title(),
+                        e
+                    );
+                    report.error = Some(e);
+                }
+            }
+            if let Some(id) = &album.id {
+                listing.albums.insert(id.clone());
+            }
+            self.report.album(report);
         }
+        self.listing.lock().unwrap().shared_albums_listed = true;
+        self.save_manifest();
+        Ok(())
+    }
+
+    fn save_manifest(&self) {
+        if self.dry_run.is_some() {
+            return;
+        }
+        self.manifest
+            .lock()
+            .unwrap()
+            .save(&self.config)
+            .unwrap_or_else(|e| error!("Could not save the known media: {}", e));
+    }
+
+    /// Removes what is no longer in Google Photos from the archive (or only shows what would
+    /// be removed, without `apply`). Only what was listed in this run is considered.
+    pub fn mirror(&self, apply: bool, max_deletions: usize) -> io::Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let plan = Plan::new(
+            &manifest,
+            &self.listing.lock().unwrap(),
+            self.storage.as_ref(),
+        )?;
+        mirror::mirror(
+            &plan,
+            self.storage.as_ref(),
+            &mut manifest,
+            apply,
+            max_deletions,
+        );
+        drop(manifest);
+        self.save_manifest();
         Ok(())
     }
 }
+
+fn album_report(album: &Album, shared: bool) -> AlbumReport {
+    AlbumReport {
+        id: album.id.clone(),
+        title: album.title.clone(),
+        shared,
+        ..AlbumReport::default()
+    }
+}
+
+/// The albums with a title matching `album_filter`.
+pub fn list_albums(hub: &Hub, album_filter: Option<Regex>) -> Result<Vec<Album>> {
+    info!("Getting albums metadata");
+    let mut page_token = String::from("");
+    let mut albums_found = Vec::new();
+
+    loop {
+        if shutdown::requested() {
+            return Err(Error::Cancelled);
+        }
+        let mut builder = hub
+            .albums()
+            .list()
+            .page_size(ALBUM_LIST_MAX_PAGE_SIZE)
+            .exclude_non_app_created_data(false);
+
+        if page_token != "" {
+            builder = builder.page_token(&page_token);
+        }
+
+        let result = builder.doit();
+
+        match result {
+            Err(e) => return Err(e),
+            Ok((http_response, albums_response)) => {
+                if http_response.status == StatusCode::Ok {
+                    if let Some(albums) = albums_response.albums {
+                        albums
+                            .iter()
+                            .filter(|album| album.matches(album_filter.clone()))
+                            .for_each(|album| {
+                                albums_found.push(album.clone());
+                            });
+                    }
+                    if let Some(token) = albums_response.next_page_token {
+                        page_token = token;
+                    } else {
+                        info!("Found {} albums.", albums_found.len());
+                        return Ok(albums_found);
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// The shared albums with a title matching `album_filter`.
+pub fn list_shared_albums(hub: &Hub, album_filter: Option<Regex>) -> Result<Vec<Album>> {
+    info!("Getting shared albums metadata");
+    let mut page_token = String::from("");
+    let mut albums_found = Vec::new();
+
+    loop {
+        if shutdown::requested() {
+            return Err(Error::Cancelled);
+        }
+        let mut builder = hub
+            .shared_albums()
+            .list()
+            .page_size(ALBUM_LIST_MAX_PAGE_SIZE)
+            .exclude_non_app_created_data(false);
+
+        if page_token != "" {
+            builder = builder.page_token(&page_token);
+        }
+
+        let result = builder.doit();
+
+        match result {
+            Err(e) => return Err(e),
+            Ok((http_response, albums_response)) => {
+                if http_response.status == StatusCode::Ok {
+                    if let Some(albums) = albums_response.shared_albums {
+                        albums
+                            .iter()
+                            .filter(|album| album.matches(album_filter.clone()))
+                            .for_each(|album| {
+                                albums_found.push(album.clone());
+                            });
+                    }
+                    if let Some(token) = albums_response.next_page_token {
+                        page_token = token;
+                    } else {
+                        info!("Found {} shared albums.", albums_found.len());
+                        return Ok(albums_found);
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// The media items with these ids, with fresh download URLs. Ids that aren't found (e.g. of
+/// deleted media) are left out.
+pub fn get_media_items(hub: &Hub, ids: &[String]) -> Result<Vec<MediaItem>> {
+    let mut found = Vec::new();
+    for chunk in ids.chunks(MEDIA_BATCH_GET_MAX_IDS) {
+        if shutdown::requested() {
+            return Err(Error::Cancelled);
+        }
+        let mut builder = hub.media_items().batch_get();
+        for id in chunk {
+            builder = builder.add_media_item_ids(id);
+        }
+        let (_, response) = builder.doit()?;
+        found.extend(
+            response
+                .media_item_results
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|result| result.media_item),
+        );
+    }
+    Ok(found)
+}
+
+/// Lists every media item in the library, calling `page` with each page of them.
+pub fn list_media_items<F>(hub: &Hub, mut page: F) -> Result<()>
+where
+    F: FnMut(&[MediaItem]),
+{
+    list_media_items_from(hub, "", |items, _| page(items))
+}
+
+/// Lists the media items from the page with `page_token` (the first one if empty), calling
+/// `page` with each page of them and the token of the next one.
+pub fn list_media_items_from<F>(hub: &Hub, page_token: &str, mut page: F) -> Result<()>
+where
+    F: FnMut(&[MediaItem], Option<&str>),
+{
+    let mut page_token = page_token.to_string();
+    loop {
+        if shutdown::requested() {
+            return Err(Error::Cancelled);
+        }
+        let mut builder = hub.media_items().list().page_size(MEDIA_LIST_MAX_PAGE_SIZE);
+        if page_token != "" {
+            builder = builder.page_token(&page_token);
+        }
+        let (http_response, items_response) = builder.doit()?;
+        if http_response.status == StatusCode::Ok {
+            page(
+                &items_response.media_items.unwrap_or_default(),
+                items_response.next_page_token.as_deref(),
+            );
+            match items_response.next_page_token {
+                Some(token) => page_token = token,
+                None => return Ok(()),
+            }
+        }
+    }
+}
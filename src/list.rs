@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::io::Write;
+use std::str::FromStr;
+
+use google_photoslibrary1::{Album, MediaItem};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::filtering::Matcher;
+use crate::library::{self, Hub};
+
+/// What to list from Google Photos.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum What {
+    Albums,
+    SharedAlbums,
+    Media,
+}
+
+impl FromStr for What {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "albums" => Ok(What::Albums),
+            "shared-albums" => Ok(What::SharedAlbums),
+            "media" => Ok(What::Media),
+            _ => Err(format!("can't list {}", s)),
+        }
+    }
+}
+
+fn field(value: &Option<String>) -> &str {
+    value.as_deref().unwrap_or("")
+}
+
+fn album_line(album: &Album) -> String {
+    [
+        field(&album.id),
+        field(&album.title),
+        field(&album.media_items_count),
+    ]
+    .join("\t")
+}
+
+fn media_line(media: &MediaItem) -> String {
+    let creation_time = media
+        .media_metadata
+        .as_ref()
+        .and_then(|metadata| metadata.creation_time.clone());
+    [
+        field(&media.id),
+        field(&media.filename),
+        field(&media.mime_type),
+        field(&creation_time),
+        field(&media.description),
+    ]
+    .join("\t")
+}
+
+fn write<T: Serialize>(
+    out: &mut dyn Write,
+    json: bool,
+    value: &T,
+    line: fn(&T) -> String,
+) -> Result<(), Box<dyn Error>> {
+    if json {
+        writeln!(out, "{}", serde_json::to_string(value)?)?;
+    } else {
+        writeln!(out, "{}", line(value))?;
+    }
+    Ok(())
+}
+
+/// Writes the metadata of the albums, shared albums or media (matching the filters of
+/// `config`) to `out`, one per line: tab separated (id, title and media count for albums;
+/// id, filename, mime type, creation time and description for media) or, with `json`, as
+/// returned by Google Photos.
+pub fn list(
+    hub: &Hub,
+    config: &Config,
+    what: What,
+    json: bool,
+    out: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let options = config.options();
+    match what {
+        What::Albums | What::SharedAlbums => {
+            let albums = if what == What::Albums {
+                library::list_albums(hub, options.album_filter)?
+            } else {
+                library::list_shared_albums(hub, options.album_filter)?
+            };
+            for album in &albums {
+                write(out, json, album, album_line)?;
+            }
+        }
+        What::Media => {
+            let mut result = Ok(());
+            library::list_media_items(hub, |items| {
+                for media in items {
+                    if result.is_ok() && media.matches(options.media_filter.clone()) {
+                        result = write(out, json, media, media_line);
+                    }
+                }
+            })?;
+            result?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{SecondsFormat, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Name of the lock file, in the archive and in the token cache.
+pub const LOCK_FILE: &str = ".lock";
+
+/// The process holding a lock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Holder {
+    pub pid: u32,
+    pub host: String,
+    pub since: String,
+}
+
+impl Holder {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            host: hostname(),
+            since: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+
+    /// Whether the holder is known to be gone: a process of this host that isn't running.
+    /// Processes of other hosts can't be checked.
+    fn is_stale(&self) -> bool {
+        self.host == hostname() && !is_running(self.pid)
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    let result = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let end = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..end]).to_string()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists. EPERM means it exists but belongs to
+    // another user.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// An exclusive lock on a folder (the archive or a token cache), released when dropped.
+///
+/// The lock file is locked by the OS while it's held, which the OS releases if the process
+/// dies, so exactly one process can take over a lock left behind. The holder written in it
+/// is for the error messages, and keeps out the processes of other hosts, which may not see
+/// the OS lock.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+    file: File,
+}
+
+/// Whether `file` is still the one at `path`, and not one removed by the previous holder
+/// after it was opened.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (opened, current) = match (file.metadata(), fs::metadata(path)) {
+        (Ok(opened), Ok(current)) => (opened, current),
+        (_, Err(ref e)) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+    Ok(opened.dev() == current.dev() && opened.ino() == current.ino())
+}
+
+#[cfg(not(unix))]
+fn is_current(_file: &File, path: &Path) -> io::Result<bool> {
+    // An open file can't be removed.
+    Ok(path.exists())
+}
+
+impl Lock {
+    /// Locks `folder`, the `what` of the error messages (e.g. "the backup folder"). A lock
+    /// left behind by a process of this host that isn't running anymore is taken over.
+    pub fn acquire(folder: &Path, what: &str) -> io::Result<Self> {
+        let path = folder.join(LOCK_FILE);
+        loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            match file.try_lock() {
+                Ok(()) => (),
+                Err(TryLockError::WouldBlock) => return Err(in_use(what, &path)),
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+            if !is_current(&file, &path)? {
+                continue;
+            }
+            match Self::holder(&path) {
+                Some(holder) if holder.is_stale() => warn!(
+                    "Taking over the stale lock of {} left by process {}, which isn't running",
+                    what, holder.pid
+                ),
+                // Held without the OS lock, e.g. by a process of another host.
+                Some(_) => return Err(in_use(what, &path)),
+                // Empty, as just created (or left by a process that died creating it).
+                None => (),
+            }
+            let holder = serde_json::to_vec(&Holder::current()).map_err(io::Error::other)?;
+            file.set_len(0)?;
+            file.write_all(&holder)?;
+            debug!("Locked {:?}", path);
+            return Ok(Self { path, file });
+        }
+    }
+
+    fn holder(path: &Path) -> Option<Holder> {
+        fs::read(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+    }
+}
+
+fn in_use(what: &str, path: &Path) -> io::Error {
+    let message = match Lock::holder(path) {
+        Some(holder) => format!(
+            "{} is in use by process {} on {} since {} (remove {} if that process isn't running)",
+            what,
+            holder.pid,
+            holder.host,
+            holder.since,
+            path.display()
+        ),
+        None => format!(
+            "{} is locked by {}, remove it if no backup is running",
+            what,
+            path.display()
+        ),
+    };
+    io::Error::new(io::ErrorKind::WouldBlock, message)
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Removed while still locked, the OS lock is released when the file is closed.
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove the lock {:?}: {}", self.path, e);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+/// Locks the archive of `config` (unless it's a dry run) and its token cache for a backup.
+/// Archives in object storage can't hold a lock, so it's kept in their state folder, which only
+/// keeps out the runs of this host.
+pub fn lock_backup(config: &Config) -> io::Result<Vec<Lock>> {
+    let options = config.options();
+    let mut locks = Vec::new();
+    if !options.dry_run {
+        let folder = match options.s3 {
+            Some(_) => config.state_dir(),
+            None => config.archive(),
+        };
+        locks.push(Lock::acquire(
+            &folder,
+            &format!("the backup {}", config.location()),
+        )?);
+    }
+    locks.push(Lock::acquire(
+        &config.cache(),
+        &format!("the token in {}", config.cache().display()),
+    )?);
+    Ok(locks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock() {
+        let folder = std::env::temp_dir().join(format!("gpb-lock-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+
+        let lock = Lock::acquire(&folder, "the folder").unwrap();
+        let error = Lock::acquire(&folder, "the folder").unwrap_err();
+        assert!(error.to_string().contains(&std::process::id().to_string()));
+        drop(lock);
+        assert!(!folder.join(LOCK_FILE).exists());
+
+        // Left behind by a process that isn't running.
+        let stale = Holder {
+            pid: u32::MAX / 2,
+            ..Holder::current()
+        };
+        fs::write(folder.join(LOCK_FILE), serde_json::to_vec(&stale).unwrap()).unwrap();
+        let lock = Lock::acquire(&folder, "the folder").unwrap();
+        let holder = Lock::holder(&folder.join(LOCK_FILE)).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        drop(lock);
+        fs::remove_dir(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_take_over_race() {
+        let folder = std::env::temp_dir().join(format!("gpb-lock-race-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let stale = Holder {
+            pid: u32::MAX / 2,
+            ..Holder::current()
+        };
+        fs::write(folder.join(LOCK_FILE), serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(8));
+        let takeovers = (0..8)
+            .map(|_| {
+                let (folder, barrier) = (folder.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    Lock::acquire(&folder, "the folder")
+                })
+            })
+            .collect::<Vec<_>>();
+        // Joined before any lock is dropped, so they all raced for the same stale lock.
+        let results = takeovers
+            .into_iter()
+            .map(|takeover| takeover.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        drop(results);
+        assert!(!folder.join(LOCK_FILE).exists());
+        fs::remove_dir(&folder).unwrap();
+    }
+}
@@ -1,25 +1,582 @@
-use google_photoslibrary1::Result;
-use tokio::prelude::future::lazy;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use google_photos_backup::cli::cli;
+use chrono::Utc;
+use log::{error, info};
+use tokio::prelude::future::{lazy, Future};
+
+use google_photos_backup::auth;
+use google_photos_backup::catalog::Catalog;
+use google_photos_backup::cli::{self, cli, cli_from};
 use google_photos_backup::config::Config;
-use google_photos_backup::library::Library;
+use google_photos_backup::config_file::ConfigFile;
+use google_photos_backup::daemon;
+use google_photos_backup::dedup;
+use google_photos_backup::dry_run::Change;
+use google_photos_backup::encryption;
+use google_photos_backup::filesystem::LinkMode;
+use google_photos_backup::gallery::Gallery;
+use google_photos_backup::library::{self, Library};
+use google_photos_backup::list;
+use google_photos_backup::lock;
+use google_photos_backup::manifest::Manifest;
+use google_photos_backup::profile::{self, Profile};
+use google_photos_backup::queue::{self, Resume, WorkQueue};
+use google_photos_backup::relink;
+use google_photos_backup::shutdown;
+use google_photos_backup::status::ArchiveStatus;
+use google_photos_backup::thumbnails;
+use google_photos_backup::verify;
 use google_photos_backup::worker;
 
-fn main() -> Result<()> {
-    env_logger::init();
-    let args = cli();
-    let config = Config::new(&args);
-    let library = Library::new(&config);
+fn gallery(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = Config::new(args)?;
+    let output = args
+        .value_of("output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| config.archive().join(".gallery"));
+    let catalog = Catalog::scan(&config.archive())?;
+    Gallery::new(&catalog, &output)?.generate()?;
+    Ok(())
+}
+
+fn generate_thumbnails(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = Config::new(args)?;
+    let catalog = Catalog::scan(&config.archive())?;
+    thumbnails::generate_all(&catalog, args.is_present("force"));
+    Ok(())
+}
+
+fn dedup(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = Config::new(args)?;
+    let links = config.options().album_links;
+    if links == LinkMode::Copy {
+        return Err("a copy of each file can't be deduplicated, use another --album-links".into());
+    }
+    let catalog = Catalog::scan(&config.archive())?;
+    dedup::dedup_archive(&catalog, &Manifest::load(&config)?, links);
+    Ok(())
+}
+
+fn relink(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = Config::new(args)?;
+    let catalog = Catalog::scan(&config.archive())?;
+    relink::relink(&catalog, config.options().album_links);
+    Ok(())
+}
+
+fn restore_local(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let passphrase = encryption::passphrase(args.value_of("key_file").map(Path::new))?;
+    encryption::restore_local(
+        Path::new(args.value_of("BACKUP FOLDER").unwrap()),
+        Path::new(args.value_of("OUTPUT").unwrap()),
+        &passphrase,
+    )?;
+    Ok(())
+}
+
+fn auth(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let (command, args) = match args.subcommand() {
+        (command, Some(args)) => (command, args),
+        _ => unreachable!("auth requires a subcommand"),
+    };
+    let credentials = auth::Credentials::from_config(&Config::for_account(args)?);
+    match command {
+        "login" => auth::login(
+            &credentials,
+            args.is_present("no_browser"),
+            args.is_present("encrypt_token"),
+        )?,
+        "status" => match auth::status(&credentials)? {
+            Some(status) => {
+                println!("Token: {}", status.token_file.display());
+                println!(
+                    "Account: {}",
+                    status
+                        .account
+                        .as_deref()
+                        .unwrap_or("unknown (log in again to see it)")
+                );
+                println!("Scopes: {}", status.scopes.join(" "));
+                println!("Access token expires: {}", status.expiry.to_rfc3339());
+            }
+            None => println!("Not logged in"),
+        },
+        "logout" => {
+            if auth::logout(&credentials)? {
+                println!("Logged out");
+            } else {
+                println!("Not logged in");
+            }
+        }
+        "revoke" => {
+            if auth::revoke(&credentials)? {
+                println!("Access revoked and logged out");
+            } else {
+                println!("Not logged in");
+            }
+        }
+        _ => unreachable!("unknown auth subcommand"),
+    }
+    Ok(())
+}
+
+/// Runs `run` with the arguments of the command, or, for a profile run without a backup
+/// folder, with the arguments saved for the profile. With a backup folder, the arguments are
+/// saved as the profile when `save` is set.
+fn with_profile<F>(args: &clap::ArgMatches, save: bool, run: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&clap::ArgMatches) -> Result<(), Box<dyn Error>>,
+{
+    let name = match args.value_of("profile") {
+        Some(name) => name,
+        None => return run(args),
+    };
+    let command_line = cli::command_line();
+    let command = &command_line[1];
+    if args.is_present("BACKUP FOLDER") {
+        if save {
+            let s3 = Config::new(args)?.options().s3.is_some();
+            let saved = profile::saved_args(
+                &command_line[2..],
+                args.index_of("BACKUP FOLDER"),
+                s3,
+                &std::env::current_dir()?,
+            );
+            Profile::new(name, saved).save()?;
+        }
+        return run(args);
+    }
+    let mut profile = Profile::load(name)?;
+    profile
+        .args
+        .extend(profile::strip_profile_arg(&command_line[2..]));
+    let matches = cli_from(&profile.command_line(command))?;
+    run(matches.subcommand_matches(command).unwrap())
+}
+
+/// Parses a backup command line and runs the backup.
+fn run_command_line(command_line: &[String]) -> Result<(), Box<dyn Error>> {
+    let args = cli_from(command_line)?;
+    run_backup(args.subcommand_matches("backup").unwrap())
+}
+
+/// Options of `list` that backups have too.
+const LIST_OPTIONS: &[(&str, &str)] = &[
+    ("config", "--config"),
+    ("client_secret", "--client-secret"),
+    ("token_key_file", "--token-key-file"),
+    ("album_filter", "--album-filter"),
+    ("media_filter", "--media-filter"),
+];
 
+/// The config of a saved profile for a command that only talks to Google Photos. The saved
+/// arguments are those of a backup, so they are parsed as one, with the options of `args` added.
+fn profile_account(name: &str, args: &clap::ArgMatches) -> Result<Config, Box<dyn Error>> {
+    let mut command_line = Profile::load(name)?.command_line("backup");
+    for (arg, option) in LIST_OPTIONS {
+        if let Some(value) = args.value_of(arg) {
+            command_line.push(format!("{}={}", option, value));
+        }
+    }
+    let matches = cli_from(&command_line)?;
+    Ok(Config::for_account(
+        matches.subcommand_matches("backup").unwrap(),
+    )?)
+}
+
+fn list(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = match args.value_of("profile") {
+        Some(name) => profile_account(name, args)?,
+        None => Config::for_account(args)?,
+    };
+    let what = args.value_of("WHAT").unwrap().parse::<list::What>()?;
+    let hub = library::connect(&config)?;
+    let stdout = std::io::stdout();
+    list::list(
+        &hub,
+        &config,
+        what,
+        args.is_present("json"),
+        &mut stdout.lock(),
+    )
+}
+
+fn status(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let status = ArchiveStatus::new(&Config::new(args)?)?;
+    println!("Backup: {}", status.location);
+    println!("Storage: {}", status.storage.join(", "));
+    println!("State: {}", status.state_dir.display());
+    println!(
+        "Media: {} ({} deleted from Google Photos)",
+        status.media, status.deleted
+    );
+    println!(
+        "Albums: {} ({} shared)",
+        status.albums + status.shared_albums,
+        status.shared_albums
+    );
+    println!("Failed downloads: {}", status.failed);
+    println!("Logged in: {}", if status.logged_in { "yes" } else { "no" });
+    Ok(())
+}
+
+fn verify(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let verification = verify::verify(&Config::new(args)?)?;
+    for (id, path) in &verification.missing {
+        println!("Missing: {} ({})", path.display(), id);
+    }
+    for path in &verification.broken_links {
+        println!("Broken link: {}", path.display());
+    }
+    println!(
+        "Checked {} media items: {} missing, {} broken links",
+        verification.checked,
+        verification.missing.len(),
+        verification.broken_links.len()
+    );
+    if verification.is_ok() {
+        Ok(())
+    } else {
+        Err("the backup is incomplete".into())
+    }
+}
+
+fn profiles(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let profiles = Profile::list()?;
+    if !args.is_present("run") {
+        for profile in profiles {
+            println!("{}: {}", profile.name, profile.args.join(" "));
+        }
+        return Ok(());
+    }
+    let mut failed = Vec::new();
+    for profile in profiles {
+        if shutdown::requested() {
+            break;
+        }
+        info!("Backing up profile {}", profile.name);
+        if let Err(e) = run_command_line(&profile.command_line("backup")) {
+            error!("Backup of profile {} failed: {}", profile.name, e);
+            failed.push(profile.name);
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("backup failed for profiles: {}", failed.join(", ")).into())
+    }
+}
+
+fn jobs(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config_file = ConfigFile::load(args.value_of("config").map(Path::new))?;
+    if !args.is_present("run") {
+        for (name, job) in config_file.jobs() {
+            let folder = job.backup_folder.unwrap_or_default();
+            println!("{}: {}", name, folder.display());
+        }
+        return Ok(());
+    }
+    let mut failed = Vec::new();
+    for (name, _) in config_file.jobs() {
+        if shutdown::requested() {
+            break;
+        }
+        info!("Running job {}", name);
+        let mut command_line = vec!["google-photos-backup".to_string(), "backup".to_string()];
+        if let Some(path) = config_file.path() {
+            command_line.push("--config".to_string());
+            command_line.push(path.display().to_string());
+        }
+        command_line.push("--job".to_string());
+        command_line.push(name.clone());
+        if let Err(e) = run_command_line(&command_line) {
+            error!("Job {} failed: {}", name, e);
+            failed.push(name.clone());
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("jobs failed: {}", failed.join(", ")).into())
+    }
+}
+
+fn run_backup(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = Config::new(args)?;
+    let _locks = lock::lock_backup(&config)?;
+    let library = Library::new(&config)?;
+    let result = backup(&config, &library, false);
+    write_report(&config, &library, &result)?;
+    result
+}
+
+/// Downloads again only the media that failed in previous backups.
+fn retry_failed(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let config = Config::new(args)?;
+    if config.options().dry_run {
+        return Err("retry-failed can't be a dry run".into());
+    }
+    let _locks = lock::lock_backup(&config)?;
+    let library = Library::new(&config)?;
+    let result = backup(&config, &library, true);
+    write_report(&config, &library, &result)?;
+    result?;
+    let failures = queue::load_failures(&config.state_dir())?;
+    for failure in &failures {
+        println!(
+            "{} ({}): {}",
+            failure.path.display(),
+            failure.id,
+            failure.error
+        );
+    }
+    if failures.is_empty() {
+        println!("No failed downloads left");
+        Ok(())
+    } else {
+        Err(format!("{} downloads still fail", failures.len()).into())
+    }
+}
+
+/// Writes the report of the run, if asked to.
+fn write_report(
+    config: &Config,
+    library: &Library,
+    result: &Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let options = config.options();
+    if options.report {
+        let report = library.report();
+        if let Err(e) = result {
+            report.error(&e.to_string());
+        }
+        report
+            .finish(result.is_ok())
+            .write(options.report_file.as_deref())?;
+    }
+    Ok(())
+}
+
+/// Backs up on the schedule until stopped, with the same library (and token) for every run.
+/// On SIGHUP the config file is read again, keeping the current configuration if it's invalid.
+fn daemon(args: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    let no_schedule =
+        "the daemon needs --interval or --cron (or interval or cron in the config file)";
+    let reload = daemon::reload_flag()?;
+    shutdown::handle_signals()?;
+    let mut config = Config::new(args)?;
+    let mut schedule = config.options().schedule.ok_or(no_schedule)?;
+    let mut library = Library::new(&config)?;
+    loop {
+        let started = Utc::now();
+        info!("Starting backup");
+        let result = lock::lock_backup(&config)
+            .map_err(|e| e.into())
+            .and_then(|_locks| backup(&config, &library, false));
+        match &result {
+            Ok(()) => info!("Backup done"),
+            Err(e) => error!("Backup failed: {}", e),
+        }
+        write_report(&config, &library, &result)?;
+
+        let mut next = schedule.next(started);
+        info!("Next backup at {}", next.to_rfc3339());
+        while daemon::sleep_until(next, &reload) {
+            info!("Reloading the configuration");
+            match Config::new(args) {
+                Err(e) => error!("Invalid configuration, keeping the previous one: {}", e),
+                Ok(reloaded) => match reloaded.options().schedule {
+                    Some(reloaded_schedule) => {
+                        config = reloaded;
+                        schedule = reloaded_schedule;
+                        next = schedule.next(started);
+                        info!("Next backup at {}", next.to_rfc3339());
+                    }
+                    None => error!("{}, keeping the previous configuration", no_schedule),
+                },
+            }
+        }
+        if shutdown::requested() {
+            info!("Stopped");
+            return Ok(());
+        }
+        library = match library.reconfigure(&config) {
+            Ok(library) => library,
+            Err(e) => {
+                error!("{}, keeping the previous configuration", e);
+                config = library.config();
+                library.reconfigure(&config)?
+            }
+        };
+    }
+}
+
+/// Backs up (or only retries the failed downloads), stopping gracefully on SIGINT or SIGTERM.
+fn backup(config: &Config, library: &Library, retry_failed: bool) -> Result<(), Box<dyn Error>> {
+    shutdown::handle_signals()?;
+    let result = list_and_download(config, library, retry_failed);
+    if shutdown::requested() {
+        return Err(
+            "interrupted, the next backup will continue from where this one stopped".into(),
+        );
+    }
+    result
+}
+
+/// The download queue of the archive, with what the previous run left to do. Without it (e.g.
+/// for a dry run) nothing is resumed.
+fn open_queue(config: &Config) -> (Option<Arc<WorkQueue>>, Resume) {
+    if config.options().dry_run {
+        return (None, Resume::default());
+    }
+    match WorkQueue::open(&config.state_dir()) {
+        Ok((queue, resume)) => (Some(Arc::new(queue)), resume),
+        Err(e) => {
+            error!(
+                "Could not open the download queue, downloads can't be resumed: {}",
+                e
+            );
+            (None, Resume::default())
+        }
+    }
+}
+
+fn list_and_download(
+    config: &Config,
+    library: &Library,
+    retry_failed: bool,
+) -> Result<(), Box<dyn Error>> {
+    let options = config.options();
+    let link_after_downloads = links_after_downloads(config);
+    let (queue, resume) = open_queue(config);
     let mut runtime = worker::start();
     let (work_sender, work_receiver) = worker::channel();
 
-    runtime.spawn(lazy(move || worker::process_work(work_receiver)));
+    if !options.dry_run {
+        let worker_config = config.clone();
+        let storage = library.storage();
+        let report = library.report();
+        let receiver = work_receiver.clone();
+        let queue = queue.clone();
+        runtime.spawn(lazy(move || {
+            worker::process_work(receiver, worker_config, storage, report, queue)
+        }));
+    }
 
-    if config.options().shared_albums {
-        library.download_shared_albums(&work_sender)?;
+    if retry_failed {
+        let queue = queue
+            .as_ref()
+            .ok_or("the failed downloads can't be retried")?;
+        let failed = queue.retry_failed();
+        info!("Retrying {} failed downloads", failed.len());
+        library.resume_downloads(&failed, queue, &work_sender)?;
+    } else {
+        if let Some(queue) = &queue {
+            if !resume.pending.is_empty() {
+                info!(
+                    "Resuming {} downloads of the previous backup",
+                    resume.pending.len()
+                );
+            }
+            library.resume_downloads(&resume.pending, queue, &work_sender)?;
+        }
+        if options.shared_albums {
+            library.download_shared_albums(&work_sender)?;
+        }
+        library.download_media_items(&work_sender, queue.as_deref(), resume.listing.as_ref())?;
+        if !link_after_downloads {
+            library.download_albums()?;
+        }
+    }
+    drop(work_sender);
+    if options.dry_run {
+        let downloads = worker::plan_work(work_receiver, config, library.storage().as_ref());
+        finish_listing(config, library, retry_failed)?;
+        dry_run_report(&library.changes(), &downloads);
+    } else {
+        // The downloads are done once the worker is.
+        runtime
+            .shutdown_on_idle()
+            .wait()
+            .map_err(|_| "the downloads didn't finish")?;
+        finish_listing(config, library, retry_failed)?;
+    }
+    // An interrupted run is resumed by the next one.
+    if let (Some(queue), false) = (&queue, shutdown::requested()) {
+        queue.finish()?;
+    }
+    Ok(())
+}
+
+/// Whether album links are made once the media is downloaded, because they need the
+/// downloaded file: hard links and copies.
+fn links_after_downloads(config: &Config) -> bool {
+    match config.options().album_links {
+        LinkMode::Hardlink | LinkMode::Copy => true,
+        LinkMode::Symlink | LinkMode::Relative => false,
+    }
+}
+
+/// Links the albums (if that waited for the downloads) and mirrors Google Photos, once the
+/// library was listed in this run.
+fn finish_listing(
+    config: &Config,
+    library: &Library,
+    retry_failed: bool,
+) -> Result<(), Box<dyn Error>> {
+    if retry_failed || shutdown::requested() {
+        return Ok(());
+    }
+    let options = config.options();
+    if links_after_downloads(config) {
+        library.download_albums()?;
+    }
+    if options.mirror {
+        library.mirror(
+            options.mirror_apply && !options.dry_run,
+            options.max_deletions,
+        )?;
+    }
+    Ok(())
+}
+
+fn dry_run_report(changes: &[Change], downloads: &[PathBuf]) {
+    for change in changes {
+        println!("{}", change);
+    }
+    for path in downloads {
+        println!("Would download: {}", path.display());
+    }
+    let count = |kind: fn(&Change) -> bool| changes.iter().filter(|change| kind(change)).count();
+    println!(
+        "Dry run: {} files would be downloaded, {} folders and {} links created, {} files removed",
+        downloads.len(),
+        count(|change| matches!(change, Change::CreateDir(_))),
+        count(|change| matches!(change, Change::Link(_, _))),
+        count(|change| matches!(change, Change::Remove(_))),
+    );
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::init();
+    let args = cli();
+    match args.subcommand() {
+        ("backup", Some(backup_args)) => with_profile(backup_args, true, run_backup),
+        ("list", Some(list_args)) => list(list_args),
+        ("status", Some(status_args)) => with_profile(status_args, false, status),
+        ("verify", Some(verify_args)) => with_profile(verify_args, false, verify),
+        ("daemon", Some(daemon_args)) => with_profile(daemon_args, false, daemon),
+        ("retry-failed", Some(retry_args)) => with_profile(retry_args, false, retry_failed),
+        ("gallery", Some(gallery_args)) => gallery(gallery_args),
+        ("thumbnails", Some(thumbnails_args)) => generate_thumbnails(thumbnails_args),
+        ("dedup", Some(dedup_args)) => dedup(dedup_args),
+        ("auth", Some(auth_args)) => auth(auth_args),
+        ("jobs", Some(jobs_args)) => jobs(jobs_args),
+        ("profiles", Some(profiles_args)) => profiles(profiles_args),
+        ("relink", Some(relink_args)) => relink(relink_args),
+        ("decrypt", Some(restore_args)) => restore_local(restore_args),
+        _ => unreachable!("a command is required"),
     }
-    library.download_media_items(&work_sender)?;
-    library.download_albums()
 }
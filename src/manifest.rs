@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Config, DeletedPolicy};
+use crate::encryption;
+use crate::sidecar::Sidecar;
+use crate::storage::Storage;
+
+const MANIFEST_FILE: &str = "items.json";
+
+/// Media deleted from Google Photos is moved under this hidden folder inside the archive, in a
+/// subfolder named after the day the deletion was found.
+pub const DELETED_FOLDER: &str = ".deleted";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path of the media in the library, relative to the archive.
+    pub path: PathBuf,
+    /// Day the media was first found missing from Google Photos.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlbumEntry {
+    /// Path of the album folder, relative to the archive.
+    pub path: PathBuf,
+    pub shared: bool,
+}
+
+/// The media of the library and the albums known to be in the archive, by id. Kept in the
+/// cache so the archive itself only holds media.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub items: BTreeMap<String, ManifestEntry>,
+    #[serde(default)]
+    pub albums: BTreeMap<String, AlbumEntry>,
+}
+
+impl Manifest {
+    fn path(config: &Config) -> PathBuf {
+        config.state_dir().join(MANIFEST_FILE)
+    }
+
+    /// The passphrase of an encrypted backup, which the manifest is encrypted with too: the ids
+    /// and paths of the media are as private as the media.
+    fn passphrase(config: &Config) -> io::Result<Option<Vec<u8>>> {
+        let options = config.options();
+        if options.encrypt {
+            encryption::passphrase(options.key_file.as_deref()).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn load(config: &Config) -> io::Result<Self> {
+        Self::read(&Self::path(config), Self::passphrase(config)?.as_deref())
+    }
+
+    pub fn save(&self, config: &Config) -> io::Result<()> {
+        self.write(&Self::path(config), Self::passphrase(config)?.as_deref())
+    }
+
+    fn read(path: &Path, passphrase: Option<&[u8]>) -> io::Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        // A manifest saved before the backup was encrypted is read as it is.
+        let contents = match passphrase {
+            Some(passphrase) if encryption::is_encrypted(&contents) => {
+                encryption::decrypt_bytes(&contents, passphrase)?
+            }
+            _ => contents,
+        };
+        serde_json::from_slice(&contents).map_err(io::Error::other)
+    }
+
+    fn write(&self, path: &Path, passphrase: Option<&[u8]>) -> io::Result<()> {
+        let mut contents = serde_json::to_vec(self).map_err(io::Error::other)?;
+        if let Some(passphrase) = passphrase {
+            contents = encryption::encrypt_bytes(&contents, passphrase)?;
+        }
+        let partial = path.with_extension("json.part");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, path)
+    }
+
+    pub fn record(&mut self, id: &str, path: &Path) {
+        self.items.insert(
+            id.to_string(),
+            ManifestEntry {
+                path: path.to_path_buf(),
+                deleted: None,
+            },
+        );
+    }
+
+    pub fn record_album(&mut self, id: &str, path: &Path, shared: bool) {
+        self.albums.insert(
+            id.to_string(),
+            AlbumEntry {
+                path: path.to_path_buf(),
+                shared,
+            },
+        );
+    }
+
+    /// Ids of the known media that isn't in `listed` (every id in a complete listing of the
+    /// library).
+    pub fn missing(&self, listed: &HashSet<String>) -> Vec<String> {
+        self.items
+            .keys()
+            .filter(|id| !listed.contains(*id))
+            .cloned()
+            .collect()
+    }
+
+    /// Paths of the media in `listed`. Media with the same filename shares one path, which
+    /// stays as long as any of it is in Google Photos.
+    pub fn paths_in_use(&self, listed: &HashSet<String>) -> HashSet<PathBuf> {
+        self.items
+            .iter()
+            .filter(|(id, _)| listed.contains(*id))
+            .map(|(_, entry)| entry.path.clone())
+            .collect()
+    }
+}
+
+fn trash(storage: &dyn Storage, path: &Path, day: &str) -> io::Result<()> {
+    let trashed = Path::new(DELETED_FOLDER).join(day).join(path);
+    storage.rename(path, &trashed)?;
+    let sidecar = Sidecar::path(path);
+    if storage.exists(&sidecar) {
+        storage.rename(&sidecar, &Sidecar::path(&trashed))?;
+    }
+    Ok(())
+}
+
+/// Deals with the media that is in the archive but no longer in Google Photos, according to
+/// the policy: only reports it, or moves it to the trash folder.
+pub fn handle_deleted(
+    manifest: &mut Manifest,
+    listed: &HashSet<String>,
+    storage: &dyn Storage,
+    policy: DeletedPolicy,
+) {
+    // Media that was already reported is only reported once, but is still moved to the
+    // trash if the policy changed since.
+    let missing = manifest
+        .missing(listed)
+        .into_iter()
+        .filter(|id| policy == DeletedPolicy::Trash || manifest.items[id].deleted.is_none())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return;
+    }
+    let day = Local::now().format("%Y-%m-%d").to_string();
+    let in_use = manifest.paths_in_use(listed);
+    println!(
+        "{} media items in the archive were deleted from Google Photos",
+        missing.len()
+    );
+    for id in missing {
+        let path = manifest.items[&id].path.clone();
+        if in_use.contains(&path) {
+            // The file is the media of another id now.
+            debug!("Forgetting {}, {:?} is still in Google Photos", id, path);
+            manifest.items.remove(&id);
+            continue;
+        }
+        if policy == DeletedPolicy::Trash && !storage.exists(&path) {
+            // Removed from the archive by hand, or never downloaded: nothing to move.
+            debug!("Forgetting {}, {:?} is not in the archive", id, path);
+            manifest.items.remove(&id);
+            continue;
+        }
+        match policy {
+            DeletedPolicy::Report => {
+                println!("Deleted from Google Photos: {} ({})", path.display(), id);
+                manifest.items.get_mut(&id).unwrap().deleted = Some(day.clone());
+            }
+            DeletedPolicy::Trash => match trash(storage, &path, &day) {
+                Ok(()) => {
+                    info!(
+                        "Moved {:?} to {}/{} ({} was deleted from Google Photos)",
+                        path, DELETED_FOLDER, day, id
+                    );
+                    manifest.items.remove(&id);
+                }
+                Err(e) => error!(
+                    "Could not move deleted media {:?} to the trash: {}",
+                    path, e
+                ),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::LinkMode;
+    use crate::storage::LocalStorage;
+
+    #[test]
+    fn test_missing() {
+        let mut manifest = Manifest::default();
+        manifest.record("a", Path::new("a.jpg"));
+        manifest.record("b", Path::new("b.jpg"));
+        manifest.record("c", Path::new("c.jpg"));
+
+        let listed = ["a".to_string(), "c".to_string()].iter().cloned().collect();
+        assert_eq!(manifest.missing(&listed), vec!["b".to_string()]);
+    }
+
+    #[test]
+    fn test_trash() {
+        let root = std::env::temp_dir().join(format!("gpb-manifest-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.jpg"), "a").unwrap();
+        fs::write(root.join("b.jpg"), "b").unwrap();
+        let storage = LocalStorage::new(&root, LinkMode::Symlink);
+        let mut manifest = Manifest::default();
+        manifest.record("a", Path::new("a.jpg"));
+        manifest.record("b", Path::new("b.jpg"));
+        // Another media item with the same filename as a, deleted.
+        manifest.record("c", Path::new("a.jpg"));
+        // Deleted, and already gone from the archive.
+        manifest.record("d", Path::new("d.jpg"));
+
+        let listed = ["a".to_string()].iter().cloned().collect();
+        handle_deleted(&mut manifest, &listed, &storage, DeletedPolicy::Trash);
+        assert!(root.join("a.jpg").exists());
+        assert!(!root.join("b.jpg").exists());
+        assert_eq!(manifest.items.keys().collect::<Vec<_>>(), vec!["a"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let path = std::env::temp_dir().join(format!("gpb-items-{}.json", std::process::id()));
+        let mut manifest = Manifest::default();
+        manifest.record("a", Path::new("Secret/a.jpg"));
+
+        manifest.write(&path, Some(b"passphrase")).unwrap();
+        let contents = fs::read(&path).unwrap();
+        assert!(encryption::is_encrypted(&contents));
+        assert!(!String::from_utf8_lossy(&contents).contains("Secret"));
+        let read = Manifest::read(&path, Some(b"passphrase")).unwrap();
+        assert_eq!(read.items["a"].path, PathBuf::from("Secret/a.jpg"));
+        assert!(Manifest::read(&path, Some(b"wrong")).is_err());
+
+        // Saved before the backup was encrypted.
+        manifest.write(&path, None).unwrap();
+        let read = Manifest::read(&path, Some(b"passphrase")).unwrap();
+        assert_eq!(read.items["a"].path, PathBuf::from("Secret/a.jpg"));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{error, info};
+
+use crate::album;
+use crate::manifest::Manifest;
+use crate::sidecar::Sidecar;
+use crate::storage::Storage;
+
+/// What was listed from Google Photos during a run, to tell what the archive should contain.
+#[derive(Debug, Default)]
+pub struct Listing {
+    /// Every media id in the library, once the library was listed completely.
+    pub media: Option<HashSet<String>>,
+    /// Ids of the albums listed.
+    pub albums: HashSet<String>,
+    pub albums_listed: bool,
+    pub shared_albums_listed: bool,
+    /// Names of the links expected in each (not shared) album folder.
+    pub links: HashMap<PathBuf, HashSet<String>>,
+    /// Folders of shared albums, where media is downloaded instead of linked.
+    pub shared_album_paths: HashSet<PathBuf>,
+}
+
+/// Everything to remove from the archive to make it a mirror of Google Photos.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Media deleted from Google Photos, by id.
+    pub media: Vec<(String, PathBuf)>,
+    /// Album links to media removed from their album. Other files in album folders are kept.
+    pub links: Vec<PathBuf>,
+    /// Folders of albums that no longer exist, by id, with the files to remove from them: the
+    /// album links or, for shared albums, the downloaded media.
+    pub albums: Vec<(String, PathBuf, Vec<PathBuf>)>,
+}
+
+impl Plan {
+    pub fn new(manifest: &Manifest, listing: &Listing, storage: &dyn Storage) -> io::Result<Self> {
+        let mut plan = Plan::default();
+
+        if let Some(listed) = &listing.media {
+            let in_use = manifest.paths_in_use(listed);
+            for id in manifest.missing(listed) {
+                let path = &manifest.items[&id].path;
+                // Media with the same filename that is still in Google Photos.
+                if !in_use.contains(path) {
+                    plan.media.push((id.clone(), path.clone()));
+                }
+            }
+        }
+
+        for (album_path, expected) in &listing.links {
+            if listing.shared_album_paths.contains(album_path) {
+                continue;
+            }
+            for path in storage.list(album_path)? {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if path.parent() == Some(album_path)
+                    && !expected.contains(name.as_ref())
+                    && album::is_album_link(storage, &path)
+                {
+                    plan.links.push(path);
+                }
+            }
+        }
+
+        // Several albums can have the same folder, which is only removed if none of them
+        // exists anymore.
+        let current = manifest
+            .albums
+            .iter()
+            .filter(|(id, _)| listing.albums.contains(*id))
+            .map(|(_, album)| album.path.clone())
+            .collect::<HashSet<_>>();
+        for (id, album) in &manifest.albums {
+            let listed = if album.shared {
+                listing.shared_albums_listed
+            } else {
+                listing.albums_listed
+            };
+            if listed && !listing.albums.contains(id) && !current.contains(&album.path) {
+                let files = storage
+                    .list(&album.path)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|file| album.shared || album::is_album_link(storage, file))
+                    .collect();
+                plan.albums.push((id.clone(), album.path.clone(), files));
+            }
+        }
+        plan.links.sort();
+        plan.albums.sort();
+        Ok(plan)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deletions() == 0
+    }
+
+    /// Number of files that would be removed.
+    pub fn deletions(&self) -> usize {
+        self.media.len()
+            + self.links.len()
+            + self
+                .albums
+                .iter()
+                .map(|(_, _, files)| std::cmp::max(files.len(), 1))
+                .sum::<usize>()
+    }
+
+    pub fn preview(&self) {
+        for (id, path) in &self.media {
+            println!(
+                "Would remove: {} (deleted from Google Photos: {})",
+                path.display(),
+                id
+            );
+        }
+        for path in &self.links {
+            println!("Would remove: {} (no longer in the album)", path.display());
+        }
+        for (id, path, files) in &self.albums {
+            println!(
+                "Would remove album folder: {} with {} files (album {} no longer exists)",
+                path.display(),
+                files.len(),
+                id
+            );
+        }
+        println!("Mirror: {} files would be removed", self.deletions());
+    }
+
+    /// Removes everything in the plan, forgetting the removed media and albums.
+    pub fn apply(&self, storage: &dyn Storage, manifest: &mut Manifest) {
+        for (id, path) in &self.media {
+            let sidecar = Sidecar::path(path);
+            if storage.exists(&sidecar) {
+                remove(storage, &sidecar);
+            }
+            if remove(storage, path) {
+                manifest.items.remove(id);
+            }
+        }
+        for path in &self.links {
+            remove(storage, path);
+        }
+        for (id, path, files) in &self.albums {
+            let removed = files.iter().filter(|file| remove(storage, file)).count();
+            if removed == files.len() {
+                match storage.remove_dir(path) {
+                    Ok(()) => {
+                        info!("Removed album {} from {:?}", id, path);
+                        manifest.albums.remove(id);
+                    }
+                    Err(e) => error!("Could not remove album folder {:?}: {}", path, e),
+                }
+            }
+        }
+    }
+}
+
+fn remove(storage: &dyn Storage, path: &Path) -> bool {
+    match storage.remove(path) {
+        Ok(()) => {
+            info!("Removed {:?}", path);
+            true
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => true,
+        Err(e) => {
+            error!("Could not remove {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Previews the plan and, with `apply`, carries it out unless it removes more than
+/// `max_deletions` files. Returns whether it was carried out.
+pub fn mirror(
+    plan: &Plan,
+    storage: &dyn Storage,
+    manifest: &mut Manifest,
+    apply: bool,
+    max_deletions: usize,
+) -> bool {
+    if plan.is_empty() {
+        info!("The backup already mirrors Google Photos");
+        return false;
+    }
+    plan.preview();
+    if !apply {
+        println!("Nothing was removed, use --mirror-apply to remove these files");
+        false
+    } else if plan.deletions() > max_deletions {
+        println!(
+            "Not removing anything: {} files to remove is more than the maximum of {} (see --max-deletions)",
+            plan.deletions(),
+            max_deletions
+        );
+        false
+    } else {
+        plan.apply(storage, manifest);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::LinkMode;
+    use crate::storage::LocalStorage;
+    use std::fs;
+
+    fn set(values: &[&str]) -> HashSet<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_plan() {
+        let root = std::env::temp_dir().join(format!("gpb-mirror-{}", std::process::id()));
+        for file in &[
+            "a.jpg",
+            "b.jpg",
+            "Album/notes.txt",
+            "Old/notes.txt",
+            "Shared/s.jpg",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, file).unwrap();
+        }
+        let storage = LocalStorage::new(&root, LinkMode::Symlink);
+        for (media, link) in &[
+            ("a.jpg", "Album/a.jpg"),
+            ("b.jpg", "Album/b.jpg"),
+            ("a.jpg", "Album/0001_a.jpg"),
+            ("a.jpg", "Gone/a.jpg"),
+            ("a.jpg", "Old/a.jpg"),
+        ] {
+            let link = Path::new(link);
+            storage.create_dir(link.parent().unwrap()).unwrap();
+            storage.link(Path::new(media), link).unwrap();
+        }
+
+        let mut manifest = Manifest::default();
+        manifest.record("a", Path::new("a.jpg"));
+        manifest.record("b", Path::new("b.jpg"));
+        // Deleted, but with the filename of media that is still there.
+        manifest.record("d", Path::new("a.jpg"));
+        manifest.record_album("album", Path::new("Album"), false);
+        manifest.record_album("old", Path::new("Old"), false);
+        manifest.record_album("gone", Path::new("Gone"), false);
+        // Deleted, but with the folder of an album that is still there.
+        manifest.record_album("renamed", Path::new("Album"), false);
+        manifest.record_album("shared", Path::new("Shared"), true);
+
+        let mut listing = Listing {
+            media: Some(set(&["a"])),
+            albums: set(&["album", "shared"]),
+            albums_listed: true,
+            shared_albums_listed: true,
+            ..Listing::default()
+        };
+        listing
+            .links
+            .insert(PathBuf::from("Album"), set(&["a.jpg"]));
+        listing.links.insert(PathBuf::from("Shared"), set(&[]));
+        listing.shared_album_paths.insert(PathBuf::from("Shared"));
+
+        let plan = Plan::new(&manifest, &listing, &storage).unwrap();
+        assert_eq!(plan.media, vec![("b".to_string(), PathBuf::from("b.jpg"))]);
+        // The files that aren't links to the library are the user's.
+        assert_eq!(
+            plan.links,
+            vec![
+                PathBuf::from("Album/0001_a.jpg"),
+                PathBuf::from("Album/b.jpg")
+            ]
+        );
+        assert_eq!(
+            plan.albums,
+            vec![
+                (
+                    "gone".to_string(),
+                    PathBuf::from("Gone"),
+                    vec![PathBuf::from("Gone/a.jpg")]
+                ),
+                (
+                    "old".to_string(),
+                    PathBuf::from("Old"),
+                    vec![PathBuf::from("Old/a.jpg")]
+                )
+            ]
+        );
+        assert_eq!(plan.deletions(), 5);
+
+        assert!(!mirror(&plan, &storage, &mut manifest, false, 5));
+        assert!(!mirror(&plan, &storage, &mut manifest, true, 4));
+        assert!(root.join("b.jpg").exists());
+        assert!(mirror(&plan, &storage, &mut manifest, true, 5));
+        for removed in &[
+            "b.jpg",
+            "Album/b.jpg",
+            "Album/0001_a.jpg",
+            "Gone",
+            "Old/a.jpg",
+        ] {
+            assert!(!root.join(removed).exists());
+        }
+        for kept in &[
+            "a.jpg",
+            "Album/a.jpg",
+            "Album/notes.txt",
+            "Old/notes.txt",
+            "Shared/s.jpg",
+        ] {
+            assert!(root.join(kept).exists());
+        }
+        assert!(!manifest.items.contains_key("b"));
+        assert!(!manifest.albums.contains_key("gone"));
+        assert!(!manifest.albums.contains_key("old"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
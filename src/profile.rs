@@ -0,0 +1,280 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::filesystem::normalize;
+
+/// Options taking a path, which is made absolute when a profile is saved.
+const PATH_OPTIONS: &[&str] = &[
+    "--config",
+    "--client-secret",
+    "--token-key-file",
+    "--key-file",
+    "--report-file",
+];
+
+/// A named backup (e.g. one per Google account) with its own token, archive and options.
+/// The options are the command line arguments the profile was last run with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+fn profiles_dir() -> io::Result<PathBuf> {
+    dirs::config_dir()
+        .map(|dir| dir.join("google_photos_backup").join("profiles"))
+        .ok_or_else(|| io::Error::other("could not get the config dir"))
+}
+
+/// Profile names end up in paths, so they are kept to simple names.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid profile name {:?}, use letters, digits, - and _",
+            name
+        ))
+    }
+}
+
+/// The command line arguments without the `--profile` option.
+pub fn strip_profile_arg(args: &[String]) -> Vec<String> {
+    let mut stripped = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            args.next();
+        } else if !arg.starts_with("--profile=") {
+            stripped.push(arg.clone());
+        }
+    }
+    stripped
+}
+
+fn absolute(path: &str, cwd: &Path) -> String {
+    let path = Path::new(path);
+    path.canonicalize()
+        .unwrap_or_else(|_| normalize(&cwd.join(path)))
+        .display()
+        .to_string()
+}
+
+/// The position in `args` of the argument clap gives the index `index`. Clap counts the value
+/// of `--option=value` and `-ovalue` as an index of its own, and doesn't count `--`.
+fn position_of(args: &[String], index: usize) -> Option<usize> {
+    let mut current = 0;
+    let mut trailing = false;
+    for (position, arg) in args.iter().enumerate() {
+        current += match arg.as_str() {
+            _ if trailing => 1,
+            "--" => {
+                trailing = true;
+                0
+            }
+            arg if arg.starts_with("--") => 1 + arg.contains('=') as usize,
+            // The short options all take a value, anything after the letter is the value.
+            arg if arg.starts_with('-') && arg.len() > 2 => 2,
+            _ => 1,
+        };
+        if current == index {
+            return Some(position);
+        }
+    }
+    None
+}
+
+/// The arguments of a profile run to save, `folder` being the clap index of the backup folder:
+/// without `--profile`, and with the paths made absolute against `cwd`. With S3 the backup
+/// folder is the prefix of the keys in the bucket, which is kept as given.
+pub fn saved_args(args: &[String], folder: Option<usize>, s3: bool, cwd: &Path) -> Vec<String> {
+    let folder = folder
+        .filter(|_| !s3)
+        .and_then(|index| position_of(args, index));
+    strip_profile_arg(&absolute_paths(args, folder, cwd))
+}
+
+/// The arguments with the values of the path options, and the argument at `folder` (the
+/// backup folder), made absolute against `cwd`, so that a profile keeps using the same files
+/// wherever it is run from.
+fn absolute_paths(args: &[String], folder: Option<usize>, cwd: &Path) -> Vec<String> {
+    let mut absolute_args = Vec::new();
+    let mut path_value = false;
+    for (index, arg) in args.iter().enumerate() {
+        let option = PATH_OPTIONS.iter().find(|option| {
+            arg.strip_prefix(*option)
+                .is_some_and(|rest| rest.starts_with('='))
+        });
+        if path_value || folder == Some(index) {
+            absolute_args.push(absolute(arg, cwd));
+        } else if let Some(option) = option {
+            absolute_args.push(format!(
+                "{}={}",
+                option,
+                absolute(&arg[option.len() + 1..], cwd)
+            ));
+        } else {
+            absolute_args.push(arg.clone());
+        }
+        path_value = PATH_OPTIONS.contains(&arg.as_str());
+    }
+    absolute_args
+}
+
+impl Profile {
+    pub fn new(name: &str, args: Vec<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            args,
+        }
+    }
+
+    pub fn load(name: &str) -> io::Result<Self> {
+        let path = profiles_dir()?.join(format!("{}.json", name));
+        let contents = fs::read(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "no profile {:?} yet, run it once with a backup folder ({})",
+                    name, e
+                ),
+            )
+        })?;
+        serde_json::from_slice(&contents).map_err(io::Error::other)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let dir = profiles_dir()?;
+        fs::create_dir_all(&dir)?;
+        let contents = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        fs::write(dir.join(format!("{}.json", self.name)), contents)
+    }
+
+    pub fn list() -> io::Result<Vec<Self>> {
+        let dir = profiles_dir()?;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut names = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(".json").map(str::to_string)
+            })
+            .collect::<Vec<_>>();
+        names.sort();
+        names.iter().map(|name| Self::load(name)).collect()
+    }
+
+    /// Command line to run `command` (e.g. `backup`) with this profile.
+    pub fn command_line(&self, command: &str) -> Vec<String> {
+        let mut command_line = vec![
+            "google-photos-backup".to_string(),
+            command.to_string(),
+            "--profile".to_string(),
+            self.name.clone(),
+        ];
+        command_line.extend(self.args.iter().cloned());
+        command_line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_profile_arg() {
+        let args = [
+            "--profile",
+            "alice",
+            "--shared-albums",
+            "--profile=bob",
+            "Backup",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(strip_profile_arg(&args), vec!["--shared-albums", "Backup"]);
+    }
+
+    #[test]
+    fn test_absolute_paths() {
+        let args = [
+            "--client-secret",
+            "secret.json",
+            "--album-filter",
+            "Backup",
+            "--key-file=../key",
+            "Backup",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        let cwd = Path::new("/nonexistent/home");
+        assert_eq!(
+            absolute_paths(&args, Some(5), cwd),
+            vec![
+                "--client-secret",
+                "/nonexistent/home/secret.json",
+                "--album-filter",
+                "Backup",
+                "--key-file=/nonexistent/key",
+                "/nonexistent/home/Backup",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_saved_args() {
+        let args = [
+            "backup",
+            "--profile=alice",
+            "-aBackup",
+            "--client-secret",
+            "secret.json",
+            "--deleted=trash",
+            "Backup",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        let mut command_line = vec!["google-photos-backup".to_string()];
+        command_line.extend(args.iter().cloned());
+        let matches = crate::cli::cli_from(&command_line).unwrap();
+        let folder = matches
+            .subcommand_matches("backup")
+            .unwrap()
+            .index_of("BACKUP FOLDER");
+        let cwd = Path::new("/nonexistent/home");
+
+        let saved = saved_args(&args[1..], folder, false, cwd);
+        assert_eq!(
+            saved,
+            vec![
+                "-aBackup",
+                "--client-secret",
+                "/nonexistent/home/secret.json",
+                "--deleted=trash",
+                "/nonexistent/home/Backup",
+            ]
+        );
+        // A key prefix in the bucket, not a local folder.
+        let saved = saved_args(&args[1..], folder, true, cwd);
+        assert_eq!(saved[4], "Backup");
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("alice_2").is_ok());
+        assert!(validate_name("../alice").is_err());
+        assert!(validate_name("").is_err());
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+const QUEUE_FILE: &str = "queue.jsonl";
+const FAILURES_FILE: &str = "failed.json";
+
+/// Runs a download is attempted in before it's given up on.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// A line of the queue file, which is only appended to while a backup runs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Queued {
+        id: String,
+        path: PathBuf,
+        #[serde(default)]
+        attempts: u32,
+    },
+    Done {
+        path: PathBuf,
+    },
+    Failed {
+        path: PathBuf,
+        error: String,
+    },
+    /// A page of the media items was listed, and its media queued.
+    Page {
+        ids: Vec<String>,
+        /// The token of the next page, none after the last one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        next_page_token: Option<String>,
+    },
+    /// The run ended, with every download finished or failed.
+    Finished,
+}
+
+/// Media waiting to be downloaded to `path`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub id: String,
+    pub path: PathBuf,
+    pub attempts: u32,
+}
+
+/// A download that failed, until it succeeds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    pub id: String,
+    pub path: PathBuf,
+    /// The last error.
+    pub error: String,
+    pub failed_at: String,
+}
+
+/// The downloads that failed, in the state folder `state_dir`.
+pub fn load_failures(state_dir: &Path) -> io::Result<Vec<Failure>> {
+    match fs::read(state_dir.join(FAILURES_FILE)) {
+        Ok(contents) => serde_json::from_slice(&contents).map_err(io::Error::other),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// How far the listing of the media items went.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListingProgress {
+    /// Ids of the media listed so far.
+    pub ids: Vec<String>,
+    /// The token of the next page to list, none once everything was listed.
+    pub page_token: Option<String>,
+}
+
+/// The downloads left by a previous run.
+#[derive(Debug, Default)]
+pub struct Resume {
+    pub pending: Vec<Pending>,
+    /// How far the previous run listed the media items, if it stopped partway through, so
+    /// that the listing continues from there.
+    pub listing: Option<ListingProgress>,
+}
+
+/// Replays the records of the queue file. A last line cut short by a crash is ignored.
+fn replay<I: IntoIterator<Item = String>>(
+    lines: I,
+) -> (BTreeMap<PathBuf, Pending>, Option<ListingProgress>) {
+    let mut pending = BTreeMap::new();
+    let mut listing = ListingProgress::default();
+    for line in lines {
+        let record = match serde_json::from_str::<Record>(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("Ignoring queue record {:?}: {}", line, e);
+                continue;
+            }
+        };
+        match record {
+            Record::Queued { id, path, attempts } => {
+                pending.insert(path.clone(), Pending { id, path, attempts });
+            }
+            Record::Done { path } => {
+                pending.remove(&path);
+            }
+            Record::Failed { path, .. } => {
+                if let Some(pending) = pending.get_mut(&path) {
+                    pending.attempts += 1;
+                }
+            }
+            Record::Page {
+                ids,
+                next_page_token,
+            } => {
+                listing.ids.extend(ids);
+                listing.page_token = next_page_token;
+            }
+            Record::Finished => listing = ListingProgress::default(),
+        }
+    }
+    // A listing that went through is done again by the next run, for the media added since.
+    let listing = Some(listing).filter(|listing| listing.page_token.is_some());
+    (pending, listing)
+}
+
+/// The downloads of a backup, kept in the state folder of the archive so that a run that
+/// crashed or was stopped can be resumed without listing the whole library again.
+pub struct WorkQueue {
+    path: PathBuf,
+    file: Mutex<File>,
+    pending: Mutex<BTreeMap<PathBuf, Pending>>,
+    failures_path: PathBuf,
+    failures: Mutex<BTreeMap<PathBuf, Failure>>,
+}
+
+impl WorkQueue {
+    /// Opens the queue in `state_dir`, with what the previous run left to do.
+    pub fn open(state_dir: &Path) -> io::Result<(Self, Resume)> {
+        let path = state_dir.join(QUEUE_FILE);
+        let (mut pending, listing) = match File::open(&path) {
+            Ok(file) => replay(BufReader::new(file).lines().map_while(Result::ok)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (BTreeMap::new(), None),
+            Err(e) => return Err(e),
+        };
+        pending.retain(|path, pending| {
+            if pending.attempts >= MAX_ATTEMPTS {
+                warn!(
+                    "Giving up on {:?} ({}) after {} attempts",
+                    path, pending.id, pending.attempts
+                );
+            }
+            pending.attempts < MAX_ATTEMPTS
+        });
+        let failures = load_failures(state_dir)?
+            .into_iter()
+            .map(|failure| (failure.path.clone(), failure))
+            .collect();
+        let queue = Self {
+            file: Mutex::new(Self::compact(&path, &pending, listing.as_ref())?),
+            path,
+            pending: Mutex::new(pending.clone()),
+            failures_path: state_dir.join(FAILURES_FILE),
+            failures: Mutex::new(failures),
+        };
+        let resume = Resume {
+            pending: pending.into_values().collect(),
+            listing,
+        };
+        Ok((queue, resume))
+    }
+
+    /// Rewrites the queue file with only `pending` and `listing`, and returns it opened for
+    /// appending.
+    fn compact(
+        path: &Path,
+        pending: &BTreeMap<PathBuf, Pending>,
+        listing: Option<&ListingProgress>,
+    ) -> io::Result<File> {
+        let partial = path.with_extension("jsonl.part");
+        let mut file = File::create(&partial)?;
+        for pending in pending.values() {
+            write_record(
+                &mut file,
+                &Record::Queued {
+                    id: pending.id.clone(),
+                    path: pending.path.clone(),
+                    attempts: pending.attempts,
+                },
+            )?;
+        }
+        if let Some(listing) = listing {
+            write_record(
+                &mut file,
+                &Record::Page {
+                    ids: listing.ids.clone(),
+                    next_page_token: listing.page_token.clone(),
+                },
+            )?;
+        }
+        file.sync_all()?;
+        fs::rename(&partial, path)?;
+        OpenOptions::new().append(true).open(path)
+    }
+
+    fn append(&self, record: &Record) {
+        let mut file = self.file.lock().unwrap();
+        write_record(&mut *file, record)
+            .unwrap_or_else(|e| warn!("Could not write to the queue {:?}: {}", self.path, e));
+    }
+
+    /// Records that the media with `id` is to be downloaded to `path`.
+    pub fn queued(&self, id: &str, path: &Path) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains_key(path) {
+            return;
+        }
+        pending.insert(
+            path.to_path_buf(),
+            Pending {
+                id: id.to_string(),
+                path: path.to_path_buf(),
+                attempts: 0,
+            },
+        );
+        self.append(&Record::Queued {
+            id: id.to_string(),
+            path: path.to_path_buf(),
+            attempts: 0,
+        });
+    }
+
+    pub fn done(&self, path: &Path) {
+        if self.pending.lock().unwrap().remove(path).is_some() {
+            self.append(&Record::Done {
+                path: path.to_path_buf(),
+            });
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.remove(path).is_some() {
+            self.save_failures(&failures);
+        }
+    }
+
+    pub fn failed(&self, path: &Path, error: &str) {
+        let id = match self.pending.lock().unwrap().get_mut(path) {
+            Some(pending) => {
+                pending.attempts += 1;
+                self.append(&Record::Failed {
+                    path: path.to_path_buf(),
+                    error: error.to_string(),
+                });
+                pending.id.clone()
+            }
+            None => return,
+        };
+        let mut failures = self.failures.lock().unwrap();
+        failures.insert(
+            path.to_path_buf(),
+            Failure {
+                id,
+                path: path.to_path_buf(),
+                error: error.to_string(),
+                failed_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            },
+        );
+        self.save_failures(&failures);
+    }
+
+    fn save_failures(&self, failures: &BTreeMap<PathBuf, Failure>) {
+        let saved = if failures.is_empty() {
+            match fs::remove_file(&self.failures_path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                removed => removed,
+            }
+        } else {
+            let failures = failures.values().collect::<Vec<_>>();
+            let partial = self.failures_path.with_extension("json.part");
+            serde_json::to_vec_pretty(&failures)
+                .map_err(io::Error::other)
+                .and_then(|json| fs::write(&partial, json))
+                .and_then(|_| fs::rename(&partial, &self.failures_path))
+        };
+        saved.unwrap_or_else(|e| {
+            warn!(
+                "Could not save the failed downloads {:?}: {}",
+                self.failures_path, e
+            )
+        });
+    }
+
+    /// The downloads that failed (in this run or before) and didn't succeed since.
+    pub fn failures(&self) -> Vec<Failure> {
+        self.failures.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Queues every failed download again, with a new count of attempts, and returns them.
+    pub fn retry_failed(&self) -> Vec<Pending> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut retried = Vec::new();
+        for failure in self.failures() {
+            let retry = Pending {
+                id: failure.id,
+                path: failure.path,
+                attempts: 0,
+            };
+            self.append(&Record::Queued {
+                id: retry.id.clone(),
+                path: retry.path.clone(),
+                attempts: 0,
+            });
+            pending.insert(retry.path.clone(), retry.clone());
+            retried.push(retry);
+        }
+        retried
+    }
+
+    /// Records that the media with `ids` was listed (and queued, or found in the archive),
+    /// and the token of the next page, so that a run that stops while listing is resumed
+    /// from there.
+    pub fn listed_page(&self, ids: &[String], next_page_token: Option<&str>) {
+        self.append(&Record::Page {
+            ids: ids.to_vec(),
+            next_page_token: next_page_token.map(str::to_string),
+        });
+    }
+
+    /// Ends the run: only the downloads that failed are kept, to be tried again by the next
+    /// run.
+    pub fn finish(&self) -> io::Result<()> {
+        self.append(&Record::Finished);
+        let pending = self.pending.lock().unwrap();
+        if pending.is_empty() {
+            return fs::remove_file(&self.path);
+        }
+        *self.file.lock().unwrap() = Self::compact(&self.path, &pending, None)?;
+        Ok(())
+    }
+}
+
+fn write_record(file: &mut dyn Write, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_replay() {
+        let queued = |id: &str, path: &str| Record::Queued {
+            id: id.to_string(),
+            path: PathBuf::from(path),
+            attempts: 0,
+        };
+        let mut records = lines(&[
+            queued("a", "a.jpg"),
+            queued("b", "b.jpg"),
+            queued("c", "Album/c.jpg"),
+            Record::Done {
+                path: PathBuf::from("a.jpg"),
+            },
+            Record::Failed {
+                path: PathBuf::from("b.jpg"),
+                error: "timeout".to_string(),
+            },
+            Record::Page {
+                ids: vec!["a".to_string(), "b".to_string()],
+                next_page_token: Some("page 2".to_string()),
+            },
+            Record::Page {
+                ids: vec!["c".to_string()],
+                next_page_token: Some("page 3".to_string()),
+            },
+        ]);
+        records.push("{\"queued\":{\"id\":\"d\",\"pa".to_string());
+
+        let (pending, listing) = replay(records.clone());
+        assert_eq!(
+            listing,
+            Some(ListingProgress {
+                ids: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+                page_token: Some("page 3".to_string()),
+            })
+        );
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[Path::new("b.jpg")].attempts, 1);
+        assert_eq!(pending[Path::new("Album/c.jpg")].id, "c");
+
+        records.extend(lines(&[Record::Finished]));
+        assert!(replay(records).1.is_none());
+    }
+
+    #[test]
+    fn test_listing_resumed() {
+        let folder = std::env::temp_dir().join(format!("gpb-queue-list-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        // Stopped after listing the first page.
+        let (queue, _) = WorkQueue::open(&folder).unwrap();
+        queue.queued("a", Path::new("a.jpg"));
+        queue.queued("b", Path::new("b.jpg"));
+        queue.done(Path::new("a.jpg"));
+        queue.listed_page(&ids(&["a", "b"]), Some("page 2"));
+        drop(queue);
+
+        let (queue, resume) = WorkQueue::open(&folder).unwrap();
+        assert_eq!(resume.pending.len(), 1);
+        assert_eq!(resume.pending[0].id, "b");
+        let listing = resume.listing.unwrap();
+        assert_eq!(listing.ids, ids(&["a", "b"]));
+        assert_eq!(listing.page_token.as_deref(), Some("page 2"));
+
+        // Stopped again once the listing went through.
+        queue.queued("c", Path::new("c.jpg"));
+        queue.listed_page(&ids(&["c"]), None);
+        drop(queue);
+        let (queue, resume) = WorkQueue::open(&folder).unwrap();
+        assert_eq!(resume.pending.len(), 2);
+        assert!(resume.listing.is_none());
+
+        queue.done(Path::new("b.jpg"));
+        queue.done(Path::new("c.jpg"));
+        queue.finish().unwrap();
+        fs::remove_dir(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_failures() {
+        let folder = std::env::temp_dir().join(format!("gpb-queue-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let (path_a, path_b) = (Path::new("a.jpg"), Path::new("b.jpg"));
+
+        let (queue, _) = WorkQueue::open(&folder).unwrap();
+        queue.queued("a", path_a);
+        queue.queued("b", path_b);
+        queue.failed(path_a, "timeout");
+        queue.failed(path_b, "timeout");
+        queue.done(path_b);
+        queue.finish().unwrap();
+        let failures = load_failures(&folder).unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            (failures[0].id.as_str(), failures[0].path.as_path()),
+            ("a", path_a)
+        );
+
+        // Kept until the download succeeds, even once the queue gives up on it.
+        let (queue, _) = WorkQueue::open(&folder).unwrap();
+        let retried = queue.retry_failed();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].attempts, 0);
+        queue.done(path_a);
+        queue.finish().unwrap();
+        assert!(load_failures(&folder).unwrap().is_empty());
+        fs::remove_dir(&folder).unwrap();
+    }
+}
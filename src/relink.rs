@@ -0,0 +1,98 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{debug, info, warn};
+use regex::Regex;
+
+use crate::catalog::{Catalog, Item};
+use crate::filesystem::{self, LinkMode};
+
+/// How an album entry currently points to the media in the library.
+fn current_mode(root: &Path, item: &Item) -> Option<LinkMode> {
+    let path = root.join(&item.path);
+    match fs::read_link(&path) {
+        Ok(target) if target.is_relative() => Some(LinkMode::Relative),
+        Ok(_) => Some(LinkMode::Symlink),
+        Err(_) => hard_link_count(&path).map(|count| {
+            if count > 1 {
+                LinkMode::Hardlink
+            } else {
+                LinkMode::Copy
+            }
+        }),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn hard_link_count(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| metadata.nlink())
+}
+
+#[cfg(target_os = "windows")]
+fn hard_link_count(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// The media in the library an album entry stands for: the target of a symbolic link, or the
+/// library file with the same name (without the `--album-order` prefix) and content for hard
+/// links and copies. Media downloaded into shared albums has none.
+fn library_media(catalog: &Catalog, item: &Item) -> Option<PathBuf> {
+    if item.is_link {
+        return Some(item.target.clone());
+    }
+    let ordinal = Regex::new(r"^\d{4,}_").unwrap();
+    let name = item.name();
+    let mut candidates = vec![PathBuf::from(&name)];
+    if ordinal.is_match(&name) {
+        candidates.push(PathBuf::from(ordinal.replace(&name, "").to_string()));
+    }
+    candidates.into_iter().find(|candidate| {
+        let candidate = catalog.root.join(candidate);
+        candidate.is_file()
+            && filesystem::same_content(&candidate, &catalog.root.join(&item.path)).unwrap_or(false)
+    })
+}
+
+fn relink_item(root: &Path, media: &Path, item: &Item, mode: LinkMode) -> io::Result<()> {
+    let path = root.join(&item.path);
+    // Link next to the entry first so the entry is only replaced once the link works.
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".relink");
+    let staged = path.with_file_name(name);
+    let _ = fs::remove_file(&staged);
+    filesystem::link(&root.join(media), &staged, mode)?;
+    fs::rename(&staged, &path)
+}
+
+/// Converts every album entry pointing to media in the library to the `mode` link mode.
+pub fn relink(catalog: &Catalog, mode: LinkMode) {
+    let mut converted = 0;
+    let mut failed = 0;
+    for album in &catalog.albums {
+        for item in &album.items {
+            if current_mode(&catalog.root, item) == Some(mode) {
+                continue;
+            }
+            let media = match library_media(catalog, item) {
+                Some(media) => media,
+                None => {
+                    debug!("{:?} isn't media from the library, leaving it", item.path);
+                    continue;
+                }
+            };
+            match relink_item(&catalog.root, &media, item, mode) {
+                Ok(()) => converted += 1,
+                Err(e) => {
+                    warn!("Could not relink {:?} to {:?}: {}", item.path, media, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    info!(
+        "Converted {} album links to {:?} ({} failed)",
+        converted, mode, failed
+    );
+}
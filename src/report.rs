@@ -0,0 +1,292 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// The options a run was made with.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReportOptions {
+    pub location: String,
+    pub profile: Option<String>,
+    pub shared_albums: bool,
+    pub album_filter: Option<String>,
+    pub media_filter: Option<String>,
+    pub album_order: bool,
+    pub album_links: String,
+    pub sidecars: bool,
+    pub contributor_folders: bool,
+    pub thumbnails: bool,
+    pub s3_bucket: Option<String>,
+    pub content_addressed: bool,
+    /// Size of the tar volumes, in MiB.
+    pub tar_volumes: Option<u64>,
+    pub encrypt: bool,
+    pub deleted: String,
+    pub mirror: bool,
+    pub mirror_apply: bool,
+    pub max_deletions: usize,
+    pub dry_run: bool,
+}
+
+impl ReportOptions {
+    fn new(config: &Config) -> Self {
+        let options = config.options();
+        Self {
+            location: config.location(),
+            profile: config.profile().map(str::to_string),
+            shared_albums: options.shared_albums,
+            album_filter: options.album_filter.map(|regex| regex.to_string()),
+            media_filter: options.media_filter.map(|regex| regex.to_string()),
+            album_order: options.album_order,
+            album_links: format!("{:?}", options.album_links).to_lowercase(),
+            sidecars: options.sidecars,
+            contributor_folders: options.contributor_folders,
+            thumbnails: options.thumbnails,
+            s3_bucket: options.s3.map(|s3| s3.bucket),
+            content_addressed: options.content_addressed,
+            tar_volumes: options.volume_size.map(|size| size / 1024 / 1024),
+            encrypt: options.encrypt,
+            deleted: format!("{:?}", options.deleted).to_lowercase(),
+            mirror: options.mirror,
+            mirror_apply: options.mirror_apply,
+            max_deletions: options.max_deletions,
+            dry_run: options.dry_run,
+        }
+    }
+}
+
+/// What happened to an album (or shared album) during a run.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AlbumReport {
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub shared: bool,
+    pub path: Option<PathBuf>,
+    /// Media linked (or, for shared albums, queued for download) in the album, after the
+    /// media filter.
+    pub media: usize,
+    pub error: Option<String>,
+}
+
+/// An error during a run, with the media item it happened with, if any.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportError {
+    pub id: Option<String>,
+    pub path: Option<PathBuf>,
+    pub error: String,
+}
+
+/// Counts and errors of a backup run.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub started: String,
+    pub finished: Option<String>,
+    pub success: bool,
+    pub options: ReportOptions,
+    /// Media items listed in the library.
+    pub listed: usize,
+    /// Media items left out by the media filter.
+    pub filtered: usize,
+    pub downloaded: usize,
+    /// Media already in the archive (or linked to a copy of it already stored).
+    pub skipped: usize,
+    pub failed: usize,
+    /// Bytes downloaded.
+    pub bytes: u64,
+    pub albums: Vec<AlbumReport>,
+    pub errors: Vec<ReportError>,
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The report of the current run, updated by the library listing and the download workers.
+#[derive(Debug)]
+pub struct Report {
+    report: Mutex<RunReport>,
+}
+
+impl Report {
+    pub fn new(config: &Config) -> Self {
+        Self::with_options(ReportOptions::new(config))
+    }
+
+    fn with_options(options: ReportOptions) -> Self {
+        Self {
+            report: Mutex::new(RunReport {
+                started: now(),
+                finished: None,
+                success: false,
+                options,
+                listed: 0,
+                filtered: 0,
+                downloaded: 0,
+                skipped: 0,
+                failed: 0,
+                bytes: 0,
+                albums: Vec::new(),
+                errors: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn listed(&self, listed: usize, filtered: usize) {
+        let mut report = self.report.lock().unwrap();
+        report.listed += listed;
+        report.filtered += filtered;
+    }
+
+    pub fn downloaded(&self, bytes: u64) {
+        let mut report = self.report.lock().unwrap();
+        report.downloaded += 1;
+        report.bytes += bytes;
+    }
+
+    pub fn skipped(&self) {
+        self.report.lock().unwrap().skipped += 1;
+    }
+
+    pub fn failed(&self, id: Option<&str>, path: &Path, error: &str) {
+        let mut report = self.report.lock().unwrap();
+        report.failed += 1;
+        report.errors.push(ReportError {
+            id: id.map(str::to_string),
+            path: Some(path.to_path_buf()),
+            error: error.to_string(),
+        });
+    }
+
+    pub fn album(&self, album: AlbumReport) {
+        self.report.lock().unwrap().albums.push(album);
+    }
+
+    /// An error that isn't about a single media item, e.g. one that stopped the run.
+    pub fn error(&self, error: &str) {
+        self.report.lock().unwrap().errors.push(ReportError {
+            id: None,
+            path: None,
+            error: error.to_string(),
+        });
+    }
+
+    /// The report, as of the end of the run.
+    pub fn finish(&self, success: bool) -> RunReport {
+        let mut report = self.report.lock().unwrap();
+        report.finished = Some(now());
+        report.success = success && report.failed == 0;
+        report.clone()
+    }
+}
+
+impl RunReport {
+    /// Writes the report as JSON to `path`, or to the standard output without one.
+    pub fn write(&self, path: Option<&Path>) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        match path {
+            Some(path) => fs::write(path, json),
+            None => {
+                let stdout = io::stdout();
+                let mut stdout = stdout.lock();
+                stdout.write_all(&json)?;
+                writeln!(stdout)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts() {
+        let report = Report::with_options(ReportOptions::default());
+        report.listed(10, 2);
+        report.listed(5, 1);
+        report.downloaded(100);
+        report.downloaded(50);
+        report.skipped();
+        report.failed(Some("c"), Path::new("c.jpg"), "timeout");
+        report.error("could not list the albums");
+
+        let report = report.finish(true);
+        assert_eq!((report.listed, report.filtered), (15, 3));
+        assert_eq!((report.downloaded, report.bytes), (2, 150));
+        assert_eq!((report.skipped, report.failed), (1, 1));
+        assert_eq!(report.errors.len(), 2);
+        assert_eq!(report.errors[0].path.as_deref(), Some(Path::new("c.jpg")));
+        assert!(report.errors[1].path.is_none());
+        assert!(report.finished.is_some());
+        // A failed download fails the run.
+        assert!(!report.success);
+    }
+
+    #[test]
+    fn test_finish() {
+        let report = Report::with_options(ReportOptions::default());
+        report.skipped();
+        assert!(report.finish(true).success);
+        assert!(!report.finish(false).success);
+    }
+
+    #[test]
+    fn test_json() {
+        let report = Report::with_options(ReportOptions {
+            location: "/backup".to_string(),
+            album_links: "symlink".to_string(),
+            ..ReportOptions::default()
+        });
+        report.listed(1, 0);
+        report.album(AlbumReport {
+            id: Some("album".to_string()),
+            title: Some("Trip".to_string()),
+            path: Some(PathBuf::from("Trip")),
+            media: 1,
+            ..AlbumReport::default()
+        });
+        report.failed(Some("a"), Path::new("a.jpg"), "timeout");
+
+        let json = serde_json::to_value(report.finish(true)).unwrap();
+        let mut keys = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![
+                "albums",
+                "bytes",
+                "downloaded",
+                "errors",
+                "failed",
+                "filtered",
+                "finished",
+                "listed",
+                "options",
+                "skipped",
+                "started",
+                "success",
+            ]
+        );
+        assert_eq!(json["options"]["location"], "/backup");
+        assert_eq!(json["options"]["album_links"], "symlink");
+        assert_eq!(json["options"]["tar_volumes"], serde_json::Value::Null);
+        assert_eq!(json["listed"], 1);
+        assert_eq!(json["success"], false);
+        assert_eq!(json["albums"][0]["title"], "Trip");
+        assert_eq!(json["albums"][0]["shared"], false);
+        assert_eq!(
+            json["errors"][0],
+            serde_json::json!({"id": "a", "path": "a.jpg", "error": "timeout"})
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once, OnceLock};
+use std::time::Duration;
+
+use log::warn;
+use signal_hook::consts::TERM_SIGNALS;
+
+/// How long the downloads in progress get to finish once a backup is interrupted.
+pub const DOWNLOADS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Exit code when interrupted a second time, like a shell does for SIGINT.
+const FORCED_EXIT_CODE: i32 = 130;
+
+fn requested_flag() -> &'static Arc<AtomicBool> {
+    static REQUESTED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    REQUESTED.get_or_init(|| Arc::new(AtomicBool::new(false)))
+}
+
+/// Makes SIGINT and SIGTERM (and SIGQUIT) request a graceful shutdown instead of killing the
+/// process. A second signal kills it right away.
+pub fn handle_signals() -> io::Result<()> {
+    static REGISTER: Once = Once::new();
+    let mut result = Ok(());
+    REGISTER.call_once(|| {
+        for &signal in TERM_SIGNALS {
+            // The shutdown is only forced once the flag was set by the first signal.
+            result = signal_hook::flag::register_conditional_shutdown(
+                signal,
+                FORCED_EXIT_CODE,
+                requested_flag().clone(),
+            )
+            .and_then(|_| signal_hook::flag::register(signal, requested_flag().clone()))
+            .map(|_| ());
+            if result.is_err() {
+                return;
+            }
+        }
+    });
+    result
+}
+
+/// Whether the process was asked to stop.
+pub fn requested() -> bool {
+    let requested = requested_flag().load(Ordering::SeqCst);
+    if requested {
+        warn_once();
+    }
+    requested
+}
+
+fn warn_once() {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        warn!(
+            "Stopping: finishing the downloads in progress (for up to {}s), interrupt again to stop right away",
+            DOWNLOADS_TIMEOUT.as_secs()
+        )
+    });
+}
@@ -0,0 +1,92 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use google_photoslibrary1::MediaItem;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Contributor {
+    pub display_name: Option<String>,
+    pub profile_picture_base_url: Option<String>,
+}
+
+/// Metadata kept next to each downloaded media item (`IMG_1234.JPG.json`), so the archive is
+/// still meaningful without access to Google Photos.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub id: Option<String>,
+    pub filename: Option<String>,
+    pub description: Option<String>,
+    pub mime_type: Option<String>,
+    pub product_url: Option<String>,
+    pub creation_time: Option<String>,
+    pub width: Option<String>,
+    pub height: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub contributor: Option<Contributor>,
+}
+
+impl From<&MediaItem> for Sidecar {
+    fn from(media: &MediaItem) -> Self {
+        let metadata = media.media_metadata.clone().unwrap_or_default();
+        let (camera_make, camera_model) = match (&metadata.photo, &metadata.video) {
+            (Some(photo), _) => (photo.camera_make.clone(), photo.camera_model.clone()),
+            (None, Some(video)) => (video.camera_make.clone(), video.camera_model.clone()),
+            (None, None) => (None, None),
+        };
+        Self {
+            id: media.id.clone(),
+            filename: media.filename.clone(),
+            description: media.description.clone(),
+            mime_type: media.mime_type.clone(),
+            product_url: media.product_url.clone(),
+            creation_time: metadata.creation_time,
+            width: metadata.width,
+            height: metadata.height,
+            camera_make,
+            camera_model,
+            contributor: media.contributor_info.as_ref().map(|info| Contributor {
+                display_name: info.display_name.clone(),
+                profile_picture_base_url: info.profile_picture_base_url.clone(),
+            }),
+        }
+    }
+}
+
+impl Sidecar {
+    pub fn path(filepath: &Path) -> PathBuf {
+        let mut name = filepath.file_name().unwrap_or_default().to_os_string();
+        name.push(".json");
+        filepath.with_file_name(name)
+    }
+
+    pub fn is_sidecar(filepath: &Path) -> bool {
+        filepath.extension() == Some(OsStr::new("json"))
+    }
+
+    pub fn read(filepath: &Path) -> Option<Self> {
+        File::open(Self::path(filepath))
+            .ok()
+            .and_then(|f| serde_json::from_reader(f).ok())
+    }
+
+    pub fn write(&self, storage: &dyn Storage, filepath: &Path) {
+        let path = Self::path(filepath);
+        serde_json::to_vec_pretty(self)
+            .map_err(|e| error!("Could not serialize sidecar {:?}: {}", path, e))
+            .and_then(|json| {
+                let size = json.len() as u64;
+                storage
+                    .put(&path, Box::new(Cursor::new(json)), Some(size))
+                    .map_err(|e| error!("Could not write sidecar {:?}: {}", path, e))
+            })
+            .map(|_| debug!("Wrote sidecar {:?}", path))
+            .ok();
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::path::PathBuf;
+
+use crate::auth::Credentials;
+use crate::config::Config;
+use crate::manifest::Manifest;
+use crate::queue;
+
+/// The state of an archive, as known from the previous runs.
+#[derive(Debug)]
+pub struct ArchiveStatus {
+    pub location: String,
+    /// How the files are stored (e.g. tar volumes, encrypted).
+    pub storage: Vec<String>,
+    pub state_dir: PathBuf,
+    /// Media of the library backed up (or being backed up).
+    pub media: usize,
+    /// Media found deleted from Google Photos, still in the archive.
+    pub deleted: usize,
+    pub albums: usize,
+    pub shared_albums: usize,
+    /// Downloads that failed and didn't succeed since, see `retry-failed`.
+    pub failed: usize,
+    pub logged_in: bool,
+}
+
+impl ArchiveStatus {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let options = config.options();
+        let manifest = Manifest::load(config)?;
+
+        let mut storage = Vec::new();
+        match (&options.s3, options.volume_size) {
+            (Some(s3), _) => storage.push(format!("S3 ({})", s3.endpoint)),
+            (None, Some(size)) => {
+                storage.push(format!("tar volumes of {} MiB", size / 1024 / 1024))
+            }
+            (None, None) => storage.push(format!(
+                "folder, {} album links",
+                format!("{:?}", options.album_links).to_lowercase()
+            )),
+        }
+        if options.content_addressed {
+            storage.push("content-addressed".to_string());
+        }
+        if options.encrypt {
+            storage.push("encrypted".to_string());
+        }
+
+        let deleted = manifest
+            .items
+            .values()
+            .filter(|entry| entry.deleted.is_some())
+            .count();
+        let shared_albums = manifest
+            .albums
+            .values()
+            .filter(|album| album.shared)
+            .count();
+        Ok(Self {
+            location: config.location(),
+            storage,
+            state_dir: config.state_dir(),
+            media: manifest.items.len() - deleted,
+            deleted,
+            albums: manifest.albums.len() - shared_albums,
+            shared_albums,
+            failed: queue::load_failures(&config.state_dir())?.len(),
+            logged_in: Credentials::from_config(config).logged_in(),
+        })
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::config::Config;
+use crate::dedup::ContentAddressedStorage;
+use crate::encryption::{self, EncryptedStorage};
+use crate::filesystem::{self, LinkMode};
+use crate::s3::S3Storage;
+use crate::volumes::TarStorage;
+
+/// Where the backup is written to. Paths are relative to the root of the archive (the backup
+/// folder or, for object storage, the key prefix given as the backup folder).
+pub trait Storage: Send + Sync {
+    /// Stores everything read from `reader` at `path` and returns the number of bytes stored.
+    /// `size` is the expected size, when known.
+    fn put(&self, path: &Path, reader: Box<dyn Read + Send>, size: Option<u64>) -> io::Result<u64>;
+    fn exists(&self, path: &Path) -> bool;
+    /// Makes the content stored at `src` also available at `dst`.
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()>;
+    /// Every file stored under `prefix`, recursively.
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Removes the folder at `path` once the files in it were removed. A folder that still has
+    /// other files is kept. Storage without folders has nothing to do.
+    fn remove_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether `dst` is a link made by `link` to the content stored at `src`, so removing it
+    /// loses nothing. Storage that can't tell says it isn't.
+    fn is_link(&self, _src: &Path, _dst: &Path) -> bool {
+        false
+    }
+
+    /// Records that the media with `id` was stored at `path`.
+    fn index(&self, _path: &Path, _id: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Removes the partial files of the writes still in progress, when a backup is stopped
+    /// without waiting for them.
+    fn clean_up(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The local folder the archive is in, for the features that need to read the media
+    /// back (e.g. thumbnails). `None` for remote storage.
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+}
+
+pub fn open(config: &Config) -> io::Result<Arc<dyn Storage>> {
+    open_with(config, false)
+}
+
+/// The storage of the archive of `config`, only to read it: nothing is created in the archive
+/// (e.g. the index of tar volumes).
+pub fn open_read_only(config: &Config) -> io::Result<Arc<dyn Storage>> {
+    open_with(config, true)
+}
+
+fn open_with(config: &Config, read_only: bool) -> io::Result<Arc<dyn Storage>> {
+    let options = config.options();
+    let storage: Arc<dyn Storage> = match (options.s3, options.volume_size) {
+        (Some(options), _) => Arc::new(S3Storage::new(&options, &config.archive())?),
+        (None, Some(volume_size)) => Arc::new(
+            if read_only {
+                TarStorage::read_only(&config.archive())
+            } else {
+                TarStorage::new(&config.archive(), volume_size)
+            }
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("could not open the tar volumes: {}", e))
+            })?,
+        ),
+        (None, None) => Arc::new(LocalStorage::new(&config.archive(), options.album_links)),
+    };
+    let passphrase = if options.encrypt {
+        Some(
+            encryption::passphrase(options.key_file.as_deref()).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("could not read the encryption passphrase: {}", e),
+                )
+            })?,
+        )
+    } else {
+        None
+    };
+    let storage: Arc<dyn Storage> = match &passphrase {
+        Some(passphrase) => Arc::new(EncryptedStorage::new(storage, passphrase)),
+        None => storage,
+    };
+    // Content is hashed before it is encrypted, so duplicates are still found. The hash is
+    // keyed then, or the blob names would give the content away.
+    Ok(if options.content_addressed {
+        Arc::new(ContentAddressedStorage::with_key(
+            storage,
+            passphrase.as_deref().map(encryption::blob_key),
+        ))
+    } else {
+        storage
+    })
+}
+
+/// The backup folder on the local filesystem.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+    link_mode: LinkMode,
+    /// Partial files being written.
+    partials: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path, link_mode: LinkMode) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            link_mode,
+            partials: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    fn list_folder(&self, folder: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(folder)? {
+            let path = entry?.path();
+            if fs::metadata(&path).map(|m| m.is_dir()).unwrap_or(false) {
+                self.list_folder(&path, files)?;
+            } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                files.push(relative.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(
+        &self,
+        path: &Path,
+        mut reader: Box<dyn Read + Send>,
+        _size: Option<u64>,
+    ) -> io::Result<u64> {
+        let filepath = self.root.join(path);
+        if let Some(parent) = filepath.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so that an interrupted write doesn't leave a
+        // partial file behind that would be taken as downloaded by the next run.
+        let mut partial = filepath.clone().into_os_string();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+
+        self.partials.lock().unwrap().insert(partial.clone());
+        let written = File::create(&partial).and_then(|f| {
+            let mut writer = io::BufWriter::new(f);
+            let written = io::copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+            Ok(written)
+        });
+        self.partials.lock().unwrap().remove(&partial);
+        match written {
+            Ok(written) => {
+                fs::rename(&partial, &filepath)?;
+                Ok(written)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.root.join(path).exists()
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        filesystem::link(&self.root.join(src), &self.root.join(dst), self.link_mode)
+    }
+
+    fn is_link(&self, src: &Path, dst: &Path) -> bool {
+        let (src, dst) = (self.root.join(src), self.root.join(dst));
+        match fs::read_link(&dst) {
+            Ok(target) => {
+                let folder = dst.parent().unwrap_or(&self.root);
+                filesystem::normalize(&folder.join(target)) == filesystem::normalize(&src)
+            }
+            // Hard links and copies have the content of the media.
+            Err(_) => src.is_file() && filesystem::same_content(&src, &dst).unwrap_or(false),
+        }
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        self.list_folder(&self.root.join(prefix), &mut files)?;
+        Ok(files)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.root.join(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let to = self.root.join(to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(self.root.join(from), to)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(self.root.join(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let folder = self.root.join(path);
+        if fs::read_dir(&folder)?.next().is_some() {
+            info!("Keeping folder {:?}, which has other files", path);
+            return Ok(());
+        }
+        fs::remove_dir(folder)
+    }
+
+    fn clean_up(&self) -> io::Result<()> {
+        for partial in self.partials.lock().unwrap().drain() {
+            match fs::remove_file(&partial) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                removed => removed?,
+            }
+        }
+        Ok(())
+    }
+
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
@@ -0,0 +1,82 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use log::{debug, info, warn};
+
+use crate::catalog::Catalog;
+
+/// Thumbnails are kept in a hidden folder inside the archive so they are ignored when
+/// scanning it, with one subfolder per size mirroring the archive layout.
+pub const THUMBNAILS_FOLDER: &str = ".thumbnails";
+pub const SIZES: &[u32] = &[256, 1024];
+
+const JPEG_QUALITY: u8 = 85;
+
+/// Path of the thumbnail of `size` for the media at `relative` (relative to the archive).
+pub fn path(root: &Path, size: u32, relative: &Path) -> PathBuf {
+    let mut name = relative.file_name().unwrap_or_default().to_os_string();
+    name.push(".jpg");
+    root.join(THUMBNAILS_FOLDER)
+        .join(size.to_string())
+        .join(relative)
+        .with_file_name(name)
+}
+
+/// Generates the missing thumbnails (or all of them with `force`) for the media at `relative`.
+/// Returns whether any thumbnail was written.
+pub fn generate(root: &Path, relative: &Path, force: bool) -> image::ImageResult<bool> {
+    let missing = SIZES
+        .iter()
+        .filter(|size| force || !path(root, **size, relative).exists())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        debug!("Thumbnails already exist for {:?}", relative);
+        return Ok(false);
+    }
+
+    let img = image::open(root.join(relative))?;
+    for size in missing {
+        let thumbnail_path = path(root, *size, relative);
+        if let Some(parent) = thumbnail_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Never upscale small images, just re-encode them.
+        let thumbnail = if img.width() > *size || img.height() > *size {
+            img.thumbnail(*size, *size)
+        } else {
+            img.clone()
+        };
+        let mut writer = BufWriter::new(File::create(&thumbnail_path)?);
+        DynamicImage::ImageRgb8(thumbnail.to_rgb())
+            .write_to(&mut writer, ImageOutputFormat::JPEG(JPEG_QUALITY))?;
+        debug!("Wrote thumbnail {:?}", thumbnail_path);
+    }
+    Ok(true)
+}
+
+/// Generates thumbnails for every image in the archive, skipping the ones already generated
+/// unless `force` is set.
+pub fn generate_all(catalog: &Catalog, force: bool) {
+    let mut generated = 0;
+    let mut failed = 0;
+    for item in catalog
+        .unique_items()
+        .into_iter()
+        .filter(|item| item.is_image())
+    {
+        match generate(&catalog.root, &item.path, force) {
+            Ok(true) => generated += 1,
+            Ok(false) => (),
+            Err(e) => {
+                warn!("Could not generate thumbnails for {:?}: {}", item.path, e);
+                failed += 1;
+            }
+        }
+    }
+    info!(
+        "Generated thumbnails for {} images ({} failed)",
+        generated, failed
+    );
+}
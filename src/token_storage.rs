@@ -0,0 +1,150 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use yup_oauth2::{DiskTokenStorage, Token, TokenStorage};
+
+use crate::encryption;
+
+/// Environment variable with the passphrase of an encrypted token, when no key file is given.
+pub const TOKEN_PASSPHRASE_VAR: &str = "GOOGLE_PHOTOS_BACKUP_TOKEN_PASSPHRASE";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredToken {
+    hash: u64,
+    scopes: Vec<String>,
+    token: Token,
+}
+
+/// Token storage keeping the tokens in a file encrypted with a passphrase (in the format of
+/// encrypted backups), for hosts where others can read the cache folder.
+pub struct EncryptedTokenStorage {
+    path: PathBuf,
+    passphrase: Vec<u8>,
+    tokens: Vec<StoredToken>,
+}
+
+impl EncryptedTokenStorage {
+    pub fn new(path: &Path, passphrase: Vec<u8>) -> io::Result<Self> {
+        let tokens = match fs::read(path) {
+            Ok(encrypted) => {
+                let contents = encryption::decrypt_bytes(&encrypted, &passphrase).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("could not decrypt the token in {:?}: {}", path, e),
+                    )
+                })?;
+                serde_json::from_slice(&contents)?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            passphrase,
+            tokens,
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents = serde_json::to_vec(&self.tokens)?;
+        let encrypted = encryption::encrypt_bytes(&contents, &self.passphrase)?;
+        // Replace the file at once, a partly written token can't be decrypted.
+        let staged = self.path.with_extension("tmp");
+        fs::write(&staged, encrypted)?;
+        fs::rename(&staged, &self.path)
+    }
+}
+
+impl TokenStorage for EncryptedTokenStorage {
+    type Error = io::Error;
+
+    fn set(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        token: Option<Token>,
+    ) -> Result<(), Self::Error> {
+        self.tokens.retain(|stored| stored.hash != scope_hash);
+        if let Some(token) = token {
+            self.tokens.push(StoredToken {
+                hash: scope_hash,
+                scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+                token,
+            });
+        }
+        self.save()
+    }
+
+    /// A token for these scopes, or for more scopes including them (as the disk storage does).
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error> {
+        Ok(self
+            .tokens
+            .iter()
+            .find(|stored| {
+                stored.hash == scope_hash
+                    || scopes
+                        .iter()
+                        .all(|scope| stored.scopes.iter().any(|stored| stored == scope))
+            })
+            .map(|stored| stored.token.clone()))
+    }
+}
+
+/// Where the tokens are kept: in plaintext, or encrypted.
+pub enum TokenStore {
+    Plain(DiskTokenStorage),
+    Encrypted(EncryptedTokenStorage),
+}
+
+impl TokenStorage for TokenStore {
+    type Error = io::Error;
+
+    fn set(
+        &mut self,
+        scope_hash: u64,
+        scopes: &Vec<&str>,
+        token: Option<Token>,
+    ) -> Result<(), Self::Error> {
+        match self {
+            TokenStore::Plain(storage) => storage.set(scope_hash, scopes, token),
+            TokenStore::Encrypted(storage) => storage.set(scope_hash, scopes, token),
+        }
+    }
+
+    fn get(&self, scope_hash: u64, scopes: &Vec<&str>) -> Result<Option<Token>, Self::Error> {
+        match self {
+            TokenStore::Plain(storage) => storage.get(scope_hash, scopes),
+            TokenStore::Encrypted(storage) => storage.get(scope_hash, scopes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypted_token_storage() {
+        let path = std::env::temp_dir().join(format!("gpb-token-{}.enc", std::process::id()));
+        let token = Token {
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: None,
+            expires_in_timestamp: Some(0),
+        };
+        let mut storage = EncryptedTokenStorage::new(&path, b"secret".to_vec()).unwrap();
+        storage
+            .set(1, &vec!["a", "b"], Some(token.clone()))
+            .unwrap();
+
+        let storage = EncryptedTokenStorage::new(&path, b"secret".to_vec()).unwrap();
+        assert_eq!(storage.get(2, &vec!["b"]).unwrap(), Some(token));
+        assert_eq!(storage.get(3, &vec!["c"]).unwrap(), None);
+        assert!(!String::from_utf8_lossy(&fs::read(&path).unwrap()).contains("refresh"));
+        assert!(EncryptedTokenStorage::new(&path, b"guess".to_vec()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
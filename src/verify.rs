@@ -0,0 +1,62 @@
+use std::io;
+use std::path::PathBuf;
+
+use log::info;
+
+use crate::catalog::Catalog;
+use crate::config::Config;
+use crate::manifest::Manifest;
+use crate::storage;
+
+/// What was found checking an archive against the media it should have.
+#[derive(Debug, Default)]
+pub struct Verification {
+    /// Number of media checked.
+    pub checked: usize,
+    /// Media backed up by a previous run that isn't in the archive, by id.
+    pub missing: Vec<(String, PathBuf)>,
+    /// Links (in albums, or to the blob store) to a file that doesn't exist.
+    pub broken_links: Vec<PathBuf>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.broken_links.is_empty()
+    }
+}
+
+/// Checks that the media of the library known from previous runs is in the archive and,
+/// for a local archive, that no link is broken.
+pub fn verify(config: &Config) -> io::Result<Verification> {
+    let manifest = Manifest::load(config)?;
+    let storage = storage::open(config)?;
+    let mut verification = Verification::default();
+
+    info!("Checking {} media items", manifest.items.len());
+    for (id, entry) in &manifest.items {
+        // Media deleted from Google Photos may have been removed on purpose.
+        if entry.deleted.is_some() {
+            continue;
+        }
+        verification.checked += 1;
+        if !storage.exists(&entry.path) {
+            verification.missing.push((id.clone(), entry.path.clone()));
+        }
+    }
+
+    if let Some(root) = storage.local_root() {
+        info!("Checking the links in {:?}", root);
+        let catalog = Catalog::scan(root)?;
+        let items = catalog
+            .library
+            .iter()
+            .chain(catalog.albums.iter().flat_map(|album| album.items.iter()));
+        for item in items {
+            // Following the link fails if its target doesn't exist.
+            if !catalog.root.join(&item.path).exists() {
+                verification.broken_links.push(item.path.clone());
+            }
+        }
+    }
+    Ok(verification)
+}
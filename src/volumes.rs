@@ -0,0 +1,411 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tar::{Builder, EntryType, Header};
+
+use crate::filesystem::relative_path;
+
+/// Every entry written to the volumes, one JSON object per line. Later lines for the same path
+/// take precedence. It isn't encrypted with `--encrypt`: it only has the paths, sizes and
+/// offsets of the entries, which the headers of the tar entries have in plaintext anyway.
+pub const INDEX_FILE: &str = "index.jsonl";
+
+const VOLUME_PREFIX: &str = "volume-";
+const VOLUME_EXTENSION: &str = ".tar";
+const STAGING_FOLDER: &str = ".staging";
+const BLOCK_SIZE: u64 = 512;
+
+static STAGED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<PathBuf>,
+    pub volume: String,
+    /// Offset of the content of the entry in the volume.
+    pub offset: u64,
+    pub size: u64,
+}
+
+struct Volumes {
+    index: HashMap<PathBuf, IndexEntry>,
+    /// `None` when the volumes are only read.
+    index_file: Option<File>,
+    next_volume: usize,
+    current: Option<(String, Builder<File>)>,
+}
+
+/// Writes the backup into rolling tar volumes (`volume-00001.tar`, ...) of up to `volume_size`
+/// bytes in the backup folder, keeping the album structure as entries in the volumes.
+///
+/// Volumes are append-only: each run starts a new volume, and files can't be removed or renamed.
+pub struct TarStorage {
+    root: PathBuf,
+    volume_size: u64,
+    volumes: Mutex<Volumes>,
+    /// The staged files of the writes in progress.
+    staged: Mutex<HashSet<PathBuf>>,
+}
+
+fn volume_number(name: &str) -> Option<usize> {
+    name.strip_prefix(VOLUME_PREFIX)?
+        .strip_suffix(VOLUME_EXTENSION)?
+        .parse()
+        .ok()
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE
+}
+
+fn header(entry_type: EntryType, size: u64) -> Header {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(size);
+    header.set_mode(if entry_type == EntryType::Symlink {
+        0o777
+    } else {
+        0o644
+    });
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    );
+    header
+}
+
+/// The index file to append to, unless the volumes are opened read-only.
+fn writable(volumes: &mut Volumes) -> io::Result<&mut File> {
+    volumes.index_file.as_mut().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "the volumes are opened read-only",
+        )
+    })
+}
+
+/// Reads the index of the volumes in `root`.
+pub fn read_index(root: &Path) -> io::Result<HashMap<PathBuf, IndexEntry>> {
+    let mut index = HashMap::new();
+    let file = match File::open(root.join(INDEX_FILE)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(index),
+        Err(e) => return Err(e),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<IndexEntry>(&line) {
+            Ok(entry) => {
+                index.insert(entry.path.clone(), entry);
+            }
+            // A line cut short by an interrupted run.
+            Err(e) => error!("Ignoring invalid index entry {:?}: {}", line, e),
+        }
+    }
+    Ok(index)
+}
+
+impl TarStorage {
+    pub fn new(root: &Path, volume_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(root)?;
+        let index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(root.join(INDEX_FILE))?;
+        Self::open(root, volume_size, Some(index_file))
+    }
+
+    /// Opens the volumes in `root` to read them (e.g. for a dry run), without creating the
+    /// folder or the index. Writing to them fails.
+    pub fn read_only(root: &Path) -> io::Result<Self> {
+        Self::open(root, 0, None)
+    }
+
+    fn open(root: &Path, volume_size: u64, index_file: Option<File>) -> io::Result<Self> {
+        let index = read_index(root)?;
+        let mut last_volume = 0;
+        for entry in fs::read_dir(root)? {
+            if let Some(number) = entry?.file_name().to_str().and_then(volume_number) {
+                last_volume = std::cmp::max(last_volume, number);
+            }
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+            volume_size,
+            volumes: Mutex::new(Volumes {
+                index,
+                index_file,
+                next_volume: last_volume + 1,
+                current: None,
+            }),
+            staged: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn staged_path(&self) -> PathBuf {
+        let count = STAGED_COUNT.fetch_add(1, Ordering::SeqCst);
+        self.root
+            .join(STAGING_FOLDER)
+            .join(format!("{}-{}", std::process::id(), count))
+    }
+
+    /// Appends an entry taking `size` bytes of content, starting a new volume if it doesn't
+    /// fit in the current one, and records it in the index.
+    fn append<F>(&self, path: &Path, size: u64, link: Option<&Path>, write: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Builder<File>) -> io::Result<()>,
+    {
+        let mut volumes = self.volumes.lock().unwrap();
+        writable(&mut volumes)?;
+        if let Some((name, builder)) = &mut volumes.current {
+            let used = builder.get_mut().stream_position()?;
+            // Leave room for the header and the end of archive marker.
+            if used > 0 && used + BLOCK_SIZE + padded(size) + 2 * BLOCK_SIZE > self.volume_size {
+                info!("Volume {} is full", name);
+                builder.finish()?;
+                volumes.current = None;
+            }
+        }
+        if volumes.current.is_none() {
+            let name = format!(
+                "{}{:05}{}",
+                VOLUME_PREFIX, volumes.next_volume, VOLUME_EXTENSION
+            );
+            debug!("Starting volume {}", name);
+            let file = File::create(self.root.join(&name))?;
+            volumes.next_volume += 1;
+            volumes.current = Some((name, Builder::new(file)));
+        }
+
+        let (volume, offset) = {
+            let (name, builder) = volumes.current.as_mut().unwrap();
+            let start = builder.get_mut().stream_position()?;
+            if let Err(e) = write(builder) {
+                // Drop the partial entry so the volume stays readable.
+                let file = builder.get_mut();
+                file.set_len(start)?;
+                file.seek(io::SeekFrom::Start(start))?;
+                return Err(e);
+            }
+            let end = builder.get_mut().stream_position()?;
+            (name.clone(), end - padded(size))
+        };
+        let entry = IndexEntry {
+            path: path.to_path_buf(),
+            id: None,
+            link: link.map(Path::to_path_buf),
+            volume,
+            offset,
+            size,
+        };
+        Self::record(&mut volumes, entry)
+    }
+
+    fn record(volumes: &mut Volumes, entry: IndexEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        line.push(b'\n');
+        writable(volumes)?.write_all(&line)?;
+        volumes.index.insert(entry.path.clone(), entry);
+        Ok(())
+    }
+}
+
+impl Drop for TarStorage {
+    fn drop(&mut self) {
+        if let Ok(mut volumes) = self.volumes.lock() {
+            if let Some((name, mut builder)) = volumes.current.take() {
+                builder
+                    .finish()
+                    .unwrap_or_else(|e| error!("Could not finish volume {}: {}", name, e));
+            }
+        }
+    }
+}
+
+impl crate::storage::Storage for TarStorage {
+    fn put(
+        &self,
+        path: &Path,
+        mut reader: Box<dyn Read + Send>,
+        _size: Option<u64>,
+    ) -> io::Result<u64> {
+        writable(&mut self.volumes.lock().unwrap())?;
+        // The size of an entry goes in its header, so the content is staged first. This also
+        // keeps the downloads in parallel, only appending to the volume is serialised.
+        let staged = self.staged_path();
+        if let Some(parent) = staged.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.staged.lock().unwrap().insert(staged.clone());
+        let result = File::create(&staged)
+            .and_then(|f| {
+                let mut writer = io::BufWriter::new(f);
+                let written = io::copy(&mut reader, &mut writer)?;
+                writer.flush()?;
+                Ok(written)
+            })
+            .and_then(|written| {
+                let mut file = File::open(&staged)?;
+                self.append(path, written, None, |builder| {
+                    builder.append_data(&mut header(EntryType::Regular, written), path, &mut file)
+                })?;
+                Ok(written)
+            });
+        let _ = fs::remove_file(&staged);
+        self.staged.lock().unwrap().remove(&staged);
+        result
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.volumes.lock().unwrap().index.contains_key(path)
+    }
+
+    fn link(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        if self.exists(dst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} is already in the volumes", dst),
+            ));
+        }
+        let target = relative_path(src, dst);
+        self.append(dst, 0, Some(src), |builder| {
+            builder.append_link(&mut header(EntryType::Symlink, 0), dst, &target)
+        })
+    }
+
+    fn is_link(&self, src: &Path, dst: &Path) -> bool {
+        self.volumes
+            .lock()
+            .unwrap()
+            .index
+            .get(dst)
+            .map(|entry| entry.link.as_deref() == Some(src))
+            .unwrap_or(false)
+    }
+
+    fn list(&self, prefix: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .volumes
+            .lock()
+            .unwrap()
+            .index
+            .keys()
+            .filter(|path| path.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't remove {:?} from append-only volumes", path),
+        ))
+    }
+
+    fn rename(&self, from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't rename {:?} in append-only volumes", from),
+        ))
+    }
+
+    fn create_dir(&self, _path: &Path) -> io::Result<()> {
+        // Folders are implied by the paths of the entries.
+        Ok(())
+    }
+
+    fn index(&self, path: &Path, id: &str) -> io::Result<()> {
+        let mut volumes = self.volumes.lock().unwrap();
+        let mut entry = volumes
+            .index
+            .get(path)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?}", path)))?;
+        entry.id = Some(id.to_string());
+        Self::record(&mut volumes, entry)
+    }
+
+    fn clean_up(&self) -> io::Result<()> {
+        for staged in self.staged.lock().unwrap().drain() {
+            match fs::remove_file(&staged) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                removed => removed?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+
+    #[test]
+    fn test_volume_number() {
+        assert_eq!(volume_number("volume-00012.tar"), Some(12));
+        assert_eq!(volume_number("index.jsonl"), None);
+    }
+
+    /// Stops the backup (cleaning up the storage) once the whole content is read.
+    struct StoppingReader {
+        content: io::Cursor<Vec<u8>>,
+        storage: std::sync::Arc<TarStorage>,
+    }
+
+    impl Read for StoppingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.content.read(buf)?;
+            if read == 0 {
+                self.storage.clean_up()?;
+            }
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn test_clean_up() {
+        let root = std::env::temp_dir().join(format!("gpb-volumes-{}", std::process::id()));
+        let storage = std::sync::Arc::new(TarStorage::new(&root, 1024 * 1024).unwrap());
+        let reader = StoppingReader {
+            content: io::Cursor::new(b"photo".to_vec()),
+            storage: storage.clone(),
+        };
+        let path = Path::new("a.jpg");
+        assert!(storage.put(path, Box::new(reader), None).is_err());
+        assert!(!storage.exists(path));
+        assert_eq!(fs::read_dir(root.join(STAGING_FOLDER)).unwrap().count(), 0);
+        drop(storage);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let root = std::env::temp_dir().join(format!("gpb-volumes-ro-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let storage = TarStorage::read_only(&root).unwrap();
+        let content = Box::new(io::Cursor::new(b"photo".to_vec()));
+        assert!(storage.put(Path::new("a.jpg"), content, None).is_err());
+        assert!(storage
+            .link(Path::new("a.jpg"), Path::new("b.jpg"))
+            .is_err());
+        drop(storage);
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir(&root).unwrap();
+    }
+}
@@ -1,16 +1,25 @@
-use std::fs::File;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
-use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use crossbeam_channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
 use google_photoslibrary1::MediaItem;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use reqwest;
 use tokio::prelude::future::{lazy, Future, IntoFuture};
 use tokio::runtime::{Builder, Runtime};
 use tokio_timer::clock::Clock;
 
+use crate::config::Config;
+use crate::dedup;
 use crate::filesystem::FilesystemSafeEscaper;
+use crate::queue::WorkQueue;
+use crate::report::Report;
+use crate::shutdown;
+use crate::sidecar::Sidecar;
+use crate::storage::Storage;
+use crate::thumbnails;
 
 const MAX_BATCH_SIZE: usize = 25;
 
@@ -31,65 +40,145 @@ pub fn start() -> Runtime {
         .unwrap()
 }
 
-fn write_file(mut resp: reqwest::Response, filepath: &Path) {
-    File::create(filepath)
-        .and_then(|f| {
-            let mut writer = std::io::BufWriter::new(f);
-            match resp.copy_to(&mut writer) {
-                Ok(written) => {
-                    if let Some(size) = resp.content_length() {
-                        assert_eq!(written, size);
-                    }
-                    debug!("Got file and saved it with {} bytes written", written)
+fn write_file(
+    storage: &dyn Storage,
+    resp: reqwest::Response,
+    filepath: &Path,
+) -> Result<u64, String> {
+    let size = resp.content_length();
+    match storage.put(filepath, Box::new(resp), size) {
+        Ok(written) => {
+            if let Some(size) = size {
+                if written != size {
+                    let e = format!("got {} bytes but expected {}", written, size);
+                    error!("Could not write {:?}: {}", filepath, e);
+                    return Err(e);
                 }
-                Err(e) => error!("Could not write to file: {}", e),
-            };
-            Ok(())
-        })
-        .map_err(|e| error!("Could not create filepath {:?}: {}", filepath, e))
-        .ok();
+            }
+            debug!("Got file and saved it with {} bytes written", written);
+            Ok(written)
+        }
+        Err(e) => {
+            error!("Could not write {:?}: {}", filepath, e);
+            Err(e.to_string())
+        }
+    }
+}
+
+/// The outcome of a download.
+enum Download {
+    /// The number of bytes downloaded.
+    Done(u64),
+    /// The last error, once the retries are used up.
+    Failed(String),
+    /// Stopped by a shutdown before it succeeded, so it's left pending for the next run.
+    Interrupted,
 }
 
 /*
-In `get` we make a http request and save the body to a file. If there are any errors saving the file, we
-log but don't take any recoverable action (yet).
+In `get` we make a http request and save the body to the storage. If there are any errors saving the file, we
+log but don't take any recoverable action (yet). Stops retrying when a shutdown is requested.
 */
-fn get(client: &reqwest::Client, url: &str, filepath: &Path) {
-    let mut success = false;
+fn get(client: &reqwest::Client, storage: &dyn Storage, url: &str, filepath: &Path) -> Download {
+    let mut result = Err("interrupted".to_string());
     let mut retries = 5;
     let mut sleep_duration = 100;
 
-    while !success && retries > 0 {
+    while result.is_err() && retries > 0 && !shutdown::requested() {
         client
             .get(&format!("{}=d", url))
             .send()
             .and_then(|resp| {
                 match resp.status() {
                     reqwest::StatusCode::OK => {
-                        // TODO(nlopes): need to check if write was successful
-                        write_file(resp, filepath);
-                        success = true; // wrong assumption that if I get here, I got the file
+                        result = write_file(storage, resp, filepath);
                     }
                     status => {
                         error!("Got unexpected status code: {:?}", status);
+                        result = Err(format!("unexpected status code {}", status));
                     }
                 };
                 Ok(())
             })
-            .map_err(|e| error!("Unable to download file: {}", e))
+            .map_err(|e| {
+                error!("Unable to download file: {}", e);
+                result = Err(format!("unable to download file: {}", e));
+            })
             .unwrap_or(());
 
-        if !success {
+        if result.is_err() {
             retries -= 1;
             std::thread::sleep(Duration::from_millis(sleep_duration));
             sleep_duration *= 2;
             debug!("Retrying file download, {} retries left.", retries);
         }
     }
+    match result {
+        Ok(bytes) => Download::Done(bytes),
+        // The error may come from the shutdown itself, e.g. its clean up of partial files.
+        Err(_) if shutdown::requested() => Download::Interrupted,
+        Err(e) => Download::Failed(e),
+    }
+}
+
+fn is_photo(media: &MediaItem) -> bool {
+    match &media.mime_type {
+        Some(mime_type) => mime_type.starts_with("image/"),
+        None => false,
+    }
+}
+
+fn write_thumbnails(storage: &dyn Storage, filepath: &Path) {
+    // Thumbnails are made from the downloaded file, which is only possible locally.
+    if let Some(root) = storage.local_root() {
+        thumbnails::generate(root, filepath, false)
+            .map_err(|e| error!("Could not generate thumbnails for {:?}: {}", filepath, e))
+            .ok();
+    }
+}
+
+/// Links `filepath` to the media if it was already stored somewhere else in the archive.
+pub(crate) fn link_known(storage: &dyn Storage, media: &MediaItem, filepath: &Path) -> bool {
+    match &media.id {
+        Some(id) if storage.exists(&dedup::id_path(id)) => {
+            storage.link(&dedup::id_path(id), filepath).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Paths of the media that `process_work` would download, for a dry run. `storage` is
+/// expected to only record the links it would make.
+pub fn plan_work(
+    receiver: Receiver<(MediaItem, PathBuf)>,
+    config: &Config,
+    storage: &dyn Storage,
+) -> Vec<PathBuf> {
+    let content_addressed = config.options().content_addressed;
+    let mut planned = HashSet::new();
+    let mut downloads = Vec::new();
+    for (media, basepath) in receiver {
+        if let Some(filename) = &media.filename {
+            let filepath = basepath.join(filename.escape());
+            if storage.exists(&filepath) || planned.contains(&filepath) {
+                debug!("File already exists, ignoring file {:?}", filepath);
+            } else if content_addressed && link_known(storage, &media, &filepath) {
+                debug!("Media already stored, would link {:?}", filepath);
+            } else {
+                planned.insert(filepath.clone());
+                downloads.push(filepath);
+            }
+        }
+    }
+    downloads
 }
 
 pub fn process_work(
     receiver: Receiver<(MediaItem, PathBuf)>,
+    config: Config,
+    storage: Arc<dyn Storage>,
+    report: Arc<Report>,
+    queue: Option<Arc<WorkQueue>>,
 ) -> impl Future<Item = (), Error = ()> {
     let mut builder = Builder::new();
     let mut runtime = builder
@@ -102,18 +191,48 @@ pub fn process_work(
         .unwrap();
 
     let mut batch = Vec::new();
+    // Media can be sent twice, e.g. when resumed and listed again.
+    let mut downloading = HashSet::new();
     let mut expired = false;
     let client = reqwest::Client::new();
+    let sidecars = config.options().sidecars;
+    let thumbnails = config.options().thumbnails;
+    let content_addressed = config.options().content_addressed;
 
     loop {
+        if shutdown::requested() {
+            // What wasn't started yet is left for the next run.
+            debug!("Stopping, dropping {} queued downloads", batch.len());
+            batch.clear();
+            break;
+        }
         match receiver.recv_timeout(Duration::from_secs(1)) {
             Ok((media, basepath)) => {
                 if let Some(filename) = &media.filename {
                     let filepath = basepath.join(filename.escape());
-                    if filepath.exists() {
+                    if downloading.contains(&filepath) {
+                        debug!("Already downloading {:?}", filepath);
+                    } else if storage.exists(&filepath) {
                         debug!("File already exists, ignoring file {:?}", filepath);
+                        report.skipped();
+                        if let Some(queue) = &queue {
+                            queue.done(&filepath);
+                        }
+                        if sidecars && !storage.exists(&Sidecar::path(&filepath)) {
+                            Sidecar::from(&media).write(storage.as_ref(), &filepath);
+                        }
+                    } else if content_addressed && link_known(storage.as_ref(), &media, &filepath) {
+                        debug!("Media already stored, linked {:?}", filepath);
+                        report.skipped();
+                        if let Some(queue) = &queue {
+                            queue.done(&filepath);
+                        }
                     } else {
                         debug!("Downloading {} to {:?}", filename, basepath);
+                        if let (Some(queue), Some(id)) = (&queue, &media.id) {
+                            queue.queued(id, &filepath);
+                        }
+                        downloading.insert(filepath.clone());
                         batch.push((media, filepath));
                     }
                 }
@@ -131,10 +250,46 @@ pub fn process_work(
         if b.len() > MAX_BATCH_SIZE || expired {
             for (media, filepath) in b {
                 let client = client.clone();
+                let storage = storage.clone();
+                let report = report.clone();
+                let queue = queue.clone();
                 runtime.spawn(lazy(move || {
+                    if shutdown::requested() {
+                        return Ok(()).into_future();
+                    }
                     debug!("Downloading {:?}", filepath);
-                    if let Some(url) = media.base_url {
-                        get(&client, &url, &filepath);
+                    let downloaded = match &media.base_url {
+                        Some(url) => get(&client, storage.as_ref(), url, &filepath),
+                        None => Download::Failed("no download URL".to_string()),
+                    };
+                    match downloaded {
+                        Download::Done(bytes) => {
+                            report.downloaded(bytes);
+                            if let Some(queue) = &queue {
+                                queue.done(&filepath);
+                            }
+                            if sidecars {
+                                Sidecar::from(&media).write(storage.as_ref(), &filepath);
+                            }
+                            if thumbnails && is_photo(&media) {
+                                write_thumbnails(storage.as_ref(), &filepath);
+                            }
+                            if let Some(id) = &media.id {
+                                storage
+                                    .index(&filepath, id)
+                                    .map_err(|e| error!("Could not index {:?}: {}", filepath, e))
+                                    .ok();
+                            }
+                        }
+                        Download::Failed(e) => {
+                            report.failed(media.id.as_deref(), &filepath, &e);
+                            if let Some(queue) = &queue {
+                                queue.failed(&filepath, &e);
+                            }
+                        }
+                        Download::Interrupted => {
+                            debug!("Stopped downloading {:?}, left for the next run", filepath);
+                        }
                     }
                     Ok(()).into_future()
                 }));
@@ -145,9 +300,32 @@ pub fn process_work(
     }
 
     info!("Finishing downloading media");
-    runtime
-        .shutdown_on_idle()
-        .wait()
-        .expect("unable to shutdown batch processing runtime");
+    if shutdown::requested() {
+        finish_in_time(runtime, storage.as_ref());
+    } else {
+        runtime
+            .shutdown_on_idle()
+            .wait()
+            .expect("unable to shutdown batch processing runtime");
+    }
     Ok(()).into_future()
 }
+
+/// Waits for the downloads in progress to finish, for up to `shutdown::DOWNLOADS_TIMEOUT`,
+/// removing the partial files of the ones that don't.
+fn finish_in_time(runtime: Runtime, storage: &dyn Storage) {
+    let (done_sender, done_receiver) = bounded(1);
+    std::thread::spawn(move || {
+        let _ = runtime.shutdown_on_idle().wait();
+        let _ = done_sender.send(());
+    });
+    if done_receiver
+        .recv_timeout(shutdown::DOWNLOADS_TIMEOUT)
+        .is_err()
+    {
+        warn!("Downloads still in progress, stopping them");
+        storage
+            .clean_up()
+            .unwrap_or_else(|e| error!("Could not remove partial downloads: {}", e));
+    }
+}